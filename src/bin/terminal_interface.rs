@@ -1,20 +1,41 @@
 use std::env;
-use std::io;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use tp2::common::log::{LogLevel, Logger};
-use tp2::structures::interface::Interface;
+use tp2::common::order_entry::{parse_order_line, ORDER_LINE_FORMAT};
+use tp2::structures::interface::{GetReport, Interface, SubmitOrder};
+use tp2::structures::interface_config::{InterfaceConfig, OrderSource};
+use tp2::structures::order_report::InterfaceReport;
+use tp2::structures::pricing::PricingConfig;
 
-type Args = (usize, String);
+const USAGE: &str = "Usage: cargo run --bin terminal_interface <ID> (<FILE> | --interactive | --watch DIR) [--robots IP:PUERTO,...] [--peers IP:PUERTO,...] [--format json|csv|yaml] [--results FILE] [--timeout SEGUNDOS] [--sla SEGUNDOS] [--prices FILE] [--journal FILE]";
+// Espacio entre ids de pedidos cargados a mano de distintas interfaces
+const INTERACTIVE_IDS: usize = 1_000_000;
+// Cada cuanto se revisa si ya terminaron todos los pedidos
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Codigos de salida: algun pedido rechazado o incompleto, o pedidos sin terminar
+const EXIT_FAILURES: i32 = 2;
+const EXIT_UNFINISHED: i32 = 3;
 
-fn parse_args() -> Args {
+fn parse_addr(value: &str) -> SocketAddr {
+    match value.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            println!("Invalid address {}", value);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_args() -> InterfaceConfig {
     let args: Vec<String> = env::args().collect();
     if args.len() < 3 {
-        Logger.log(
-            LogLevel::Error,
-            "Usage: cargo run --bin terminal_interface <ID> <FILE>",
-        );
+        Logger.log(LogLevel::Error, USAGE);
         process::exit(1);
     }
     let id: usize = match args[1].parse() {
@@ -24,16 +45,193 @@ fn parse_args() -> Args {
             std::process::exit(1);
         }
     };
-    let file = &args[2];
-    println!("id {}, file: {}", id, file);
-    (id, file.to_string())
+    let (source, options) = match (args[2].as_str(), args.get(3)) {
+        ("--interactive", _) => (OrderSource::Interactive, 3),
+        ("--watch", Some(dir)) => (OrderSource::Watch(PathBuf::from(dir)), 4),
+        ("--watch", None) => {
+            Logger.log(LogLevel::Error, USAGE);
+            process::exit(1);
+        }
+        (file, _) => (OrderSource::File(file.to_string()), 3),
+    };
+    println!("id {}, source: {:?}", id, source);
+    let mut config = match InterfaceConfig::new(id, source) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut rest = args[options..].iter();
+    while let Some(arg) = rest.next() {
+        match (arg.as_str(), rest.next()) {
+            ("--robots", Some(value)) => {
+                config.robots = value
+                    .split(',')
+                    .filter(|robot| !robot.is_empty())
+                    .map(parse_addr)
+                    .collect()
+            }
+            ("--peers", Some(value)) => {
+                config.peers = value
+                    .split(',')
+                    .filter(|peer| !peer.is_empty())
+                    .map(parse_addr)
+                    .collect()
+            }
+            ("--format", Some(value)) => match value.parse() {
+                Ok(format) => config.format = Some(format),
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                }
+            },
+            ("--prices", Some(value)) => match PricingConfig::from_file(value) {
+                Ok(pricing) => config.pricing = pricing,
+                Err(e) => {
+                    println!("Invalid prices file {}: {}", value, e);
+                    process::exit(1);
+                }
+            },
+            ("--journal", Some(value)) => config.journal = value.to_string(),
+            ("--results", Some(value)) => config.results = value.to_string(),
+            ("--timeout", Some(value)) => match value.parse() {
+                Ok(secs) => config.timeout = Duration::from_secs(secs),
+                Err(_) => {
+                    println!("Invalid timeout {}", value);
+                    process::exit(1);
+                }
+            },
+            ("--sla", Some(value)) => match value.parse() {
+                Ok(secs) => config.sla = Duration::from_secs(secs),
+                Err(_) => {
+                    println!("Invalid SLA {}", value);
+                    process::exit(1);
+                }
+            },
+            _ => {
+                Logger.log(LogLevel::Error, USAGE);
+                process::exit(1);
+            }
+        }
+    }
+    config
+}
+
+/// Lee pedidos de la entrada estandar hasta `quit` o fin de archivo y los
+/// manda a la interfaz, numerandolos desde `next`. El estado de cada pedido lo
+/// va imprimiendo la interfaz.
+fn read_orders(id: usize, mut next: usize, interface: Addr<Interface>) {
+    println!("Enter orders as {} (help, quit)", ORDER_LINE_FORMAT);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        match line.trim() {
+            "" => continue,
+            "quit" | "exit" => break,
+            "help" => {
+                println!(
+                    "{}, e.g. 7;Chocolate,Dulce de Leche;0.5;2000",
+                    ORDER_LINE_FORMAT
+                );
+                continue;
+            }
+            line => match parse_order_line(id * INTERACTIVE_IDS + next, line) {
+                Ok(order) => {
+                    next += 1;
+                    interface.do_send(SubmitOrder { order });
+                }
+                Err(reason) => println!("Invalid order: {}", reason),
+            },
+        }
+    }
+}
+
+/// Espera a que todos los pedidos lleguen a un estado final o a que se
+/// cumpla el plazo, y devuelve el estado en ese momento.
+async fn wait_results(
+    interface: &Addr<Interface>,
+    timeout: Duration,
+) -> io::Result<InterfaceReport> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let report = interface.send(GetReport).await.map_err(io::Error::other)?;
+        if report.is_finished() || Instant::now() >= deadline {
+            return Ok(report);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn write_results(path: &str, report: &InterfaceReport) -> io::Result<()> {
+    let content = serde_json::to_string_pretty(report)?;
+    std::fs::write(path, content)
 }
 
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
-    let (id, file) = parse_args();
-    let interface = Interface::new(id, file)?;
-    interface.start();
-    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+    let config = parse_args();
+    let id = config.id;
+    let source = config.source.clone();
+    let (results, timeout) = (config.results.clone(), config.timeout);
+    let journal = config.journal.clone();
+    let interface = Interface::new(config)?;
+    let addr = interface.start();
+    match source {
+        OrderSource::File(_) => {}
+        OrderSource::Interactive => {
+            // Se sigue despues de los pedidos cargados a mano que se retomaron
+            // del diario
+            let report = addr.send(GetReport).await.map_err(io::Error::other)?;
+            let ids = id * INTERACTIVE_IDS..(id + 1) * INTERACTIVE_IDS;
+            let next = report
+                .orders
+                .iter()
+                .filter(|order| ids.contains(&order.id))
+                .map(|order| order.id - ids.start + 1)
+                .max()
+                .unwrap_or(1);
+            let reader = addr.clone();
+            let _ = tokio::task::spawn_blocking(move || read_orders(id, next, reader)).await;
+        }
+        // Atiende la carpeta hasta recibir SIGINT y despues espera lo pendiente
+        OrderSource::Watch(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+    let report = wait_results(&addr, timeout).await?;
+    write_results(&results, &report)?;
+    // Si todo termino no queda nada que retomar
+    if report.is_finished() {
+        let _ = std::fs::remove_file(&journal);
+    }
+    Logger.log(
+        LogLevel::Info,
+        format!(
+            "[Interface {}] {} completed, {} incomplete, {} rejected, {} expired, {} invalid, {} unfinished. Results in {}",
+            id,
+            report.completed,
+            report.incomplete,
+            report.rejected,
+            report.expired,
+            report.invalid.len(),
+            report.pending,
+            results
+        )
+        .as_str(),
+    );
+    if report.pending > 0 {
+        process::exit(EXIT_UNFINISHED);
+    }
+    if report.failures() > 0 {
+        process::exit(EXIT_FAILURES);
+    }
     Ok(())
 }
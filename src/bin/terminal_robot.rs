@@ -1,30 +1,112 @@
 use actix::prelude::*;
 use std::env;
 use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
 use tp2::common::log::{LogLevel, Logger};
-use tp2::structures::robot::Robot;
+use tp2::structures::robot::{Drain, Robot};
+use tp2::structures::robot_config::RobotConfig;
 
-fn parsed_args() -> usize {
+const USAGE: &str = "Uso: cargo run --bin terminal_robot <ID> [CAPACIDAD] [--addr IP:PUERTO] [--leader-addr IP:PUERTO] [--seeds IP:PUERTO,...] [--priority N] [--prefer-fresh] [--election ring|bully] [--raft] [--raft-log FILE]";
+
+fn exit_with(message: &str) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+fn parse_addr(value: &str) -> SocketAddr {
+    match value.parse() {
+        Ok(addr) => addr,
+        Err(_) => exit_with(format!("Invalid address {}", value).as_str()),
+    }
+}
+
+fn parsed_args() -> RobotConfig {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        Logger.log(LogLevel::Error, "Uso: cargo run --bin terminal_robot <ID>");
+    if args.len() < 2 {
+        Logger.log(LogLevel::Error, USAGE);
         std::process::exit(1);
     }
     let id: usize = match args[1].parse() {
         Ok(num) => num,
-        Err(_) => {
-            println!("Invalid id");
-            std::process::exit(1);
-        }
+        Err(_) => exit_with("Invalid id"),
     };
-    id
+    let mut config = match RobotConfig::new(id) {
+        Ok(config) => config,
+        Err(e) => exit_with(e.as_str()),
+    };
+
+    // Con Raft el grupo son las semillas: el de por defecto (robots 1 a 9)
+    // necesitaria 5 robots vivos para tener mayoria
+    let mut seeds_given = false;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--addr" => match rest.next() {
+                Some(value) => config.addr = parse_addr(value),
+                None => exit_with(USAGE),
+            },
+            "--leader-addr" => match rest.next() {
+                Some(value) => config.leader_addr = parse_addr(value),
+                None => exit_with(USAGE),
+            },
+            "--seeds" => match rest.next() {
+                Some(value) => {
+                    seeds_given = true;
+                    config.seeds = value
+                        .split(',')
+                        .filter(|seed| !seed.is_empty())
+                        .map(parse_addr)
+                        .collect()
+                }
+                None => exit_with(USAGE),
+            },
+            "--priority" => match rest.next().map(|value| value.parse()) {
+                Some(Ok(priority)) => config.priority = priority,
+                _ => exit_with("Invalid priority"),
+            },
+            "--prefer-fresh" => config.prefer_fresh_state = true,
+            "--raft" => config.raft = true,
+            "--raft-log" => match rest.next() {
+                Some(value) => config.raft_log = value.to_string(),
+                None => exit_with(USAGE),
+            },
+            "--election" => match rest.next().map(|value| value.parse()) {
+                Some(Ok(election)) => config.election = election,
+                _ => exit_with("Invalid election algorithm"),
+            },
+            capacity => match capacity.parse() {
+                Ok(num) if num > 0 => config.capacity = num,
+                _ => exit_with("Invalid capacity"),
+            },
+        }
+    }
+    if config.raft && !seeds_given {
+        exit_with("--raft needs --seeds with the robots of the Raft group");
+    }
+    let own_addr = config.addr;
+    config.seeds.retain(|seed| *seed != own_addr);
+    config
 }
 
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
-    let id = parsed_args();
-    Robot::new(id)?.start();
-    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+    let config = parsed_args();
+    let robot = Robot::new(config)?.start();
+
+    // Corre hasta recibir SIGINT o un mensaje Drain; en ambos casos el robot
+    // se retira ordenadamente y frena su actor al terminar
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => robot.do_send(Drain),
+        _ = wait_stopped(&robot) => return Ok(()),
+    }
+    wait_stopped(&robot).await;
 
     Ok(())
 }
+
+async fn wait_stopped(robot: &Addr<Robot>) {
+    while robot.connected() {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
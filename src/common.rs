@@ -1,3 +1,5 @@
 pub mod log;
+pub mod order_entry;
 pub mod protocol;
 pub mod read_file;
+pub mod udp;
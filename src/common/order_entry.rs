@@ -0,0 +1,102 @@
+use crate::defines::flavours::find_flavour;
+use crate::structures::order::Order;
+
+/// Tamaños que se venden, en kilos.
+pub const SIZES: [f32; 3] = [0.25, 0.5, 1.0];
+// Sabores por pedido como maximo
+const MAX_FLAVOURS: usize = 3;
+
+/// Valida los sabores de un pedido y los devuelve con su nombre en el stock.
+pub fn validate_flavours<S: AsRef<str>>(names: &[S]) -> Result<Vec<&'static str>, String> {
+    let flavours = names
+        .iter()
+        .map(|name| name.as_ref())
+        .filter(|name| !name.trim().is_empty())
+        .map(|name| find_flavour(name).ok_or(format!("unknown flavour {}", name.trim())))
+        .collect::<Result<Vec<&str>, String>>()?;
+    if flavours.is_empty() || flavours.len() > MAX_FLAVOURS {
+        return Err(format!("between 1 and {} flavours", MAX_FLAVOURS));
+    }
+    Ok(flavours)
+}
+
+pub fn validate_size(size: f32) -> Result<f32, String> {
+    if !SIZES.contains(&size) {
+        return Err(format!("size must be one of {:?}", SIZES));
+    }
+    Ok(size)
+}
+
+/// Formato de un pedido cargado a mano.
+pub const ORDER_LINE_FORMAT: &str = "cliente;sabor1,sabor2,...;tamaño;saldo";
+
+/// Arma un pedido a partir de una linea `cliente;sabores;tamaño;saldo`, por
+/// ejemplo `7;Chocolate,Dulce de Leche;0.5;2000`. Devuelve un mensaje con el
+/// motivo si la linea no es valida.
+pub fn parse_order_line(id: usize, line: &str) -> Result<Order, String> {
+    let fields: Vec<&str> = line.split(';').map(|field| field.trim()).collect();
+    if fields.len() != 4 {
+        return Err(format!("expected {}", ORDER_LINE_FORMAT));
+    }
+    let id_client: usize = fields[0]
+        .parse()
+        .map_err(|_| format!("invalid client {}", fields[0]))?;
+    let flavours = validate_flavours(&fields[1].split(',').collect::<Vec<&str>>())?;
+    let size: f32 = fields[2]
+        .parse()
+        .map_err(|_| format!("invalid size {}", fields[2]))?;
+    validate_size(size)?;
+    let cash_card: usize = fields[3]
+        .parse()
+        .map_err(|_| format!("invalid card balance {}", fields[3]))?;
+    Ok(Order::new(
+        id,
+        id_client,
+        flavours.iter().map(|flavour| flavour.to_string()).collect(),
+        0,
+        size,
+        cash_card,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_valid_line_uses_the_stock_flavour_names() {
+        let order = parse_order_line(4, " 7; chocolate ,Dulce de leche ;0.5; 2000").unwrap();
+        assert_eq!(order.id, 4);
+        assert_eq!(order.id_client, 7);
+        assert_eq!(order.products, vec!["Chocolate", "Dulce de Leche"]);
+        assert_eq!(order.amount, 0.5);
+        assert_eq!(order.card_cash, 2000);
+    }
+
+    #[test]
+    fn an_unknown_flavour_is_rejected() {
+        assert_eq!(
+            parse_order_line(1, "7;Chocolate,Pistacho;0.5;2000").unwrap_err(),
+            "unknown flavour Pistacho"
+        );
+        assert!(parse_order_line(1, "7;;0.5;2000").is_err());
+    }
+
+    #[test]
+    fn a_bad_size_is_rejected() {
+        assert_eq!(
+            parse_order_line(1, "7;Chocolate;medio;2000").unwrap_err(),
+            "invalid size medio"
+        );
+        assert!(parse_order_line(1, "7;Chocolate;0.75;2000").is_err());
+    }
+
+    #[test]
+    fn an_empty_line_is_rejected() {
+        assert_eq!(
+            parse_order_line(1, "").unwrap_err(),
+            format!("expected {}", ORDER_LINE_FORMAT)
+        );
+        assert!(parse_order_line(1, "   ").is_err());
+    }
+}
@@ -1,5 +1,9 @@
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DTO {
@@ -9,6 +13,60 @@ pub struct DTO {
     pub size_order: f32,
     pub cash_card: usize,
     pub total_amount: usize,
+    /// Direccion de la interfaz duena, a la que el robot le manda el
+    /// resultado.
+    pub owner: SocketAddr,
+    /// Interfaces que toman el pedido, en orden, si la suya no responde.
+    #[serde(default)]
+    pub takeover: Vec<SocketAddr>,
+    /// Hasta cuando se puede entregar el pedido, en milisegundos desde
+    /// UNIX_EPOCH. Pasado ese momento la interfaz anula el pago.
+    #[serde(default)]
+    pub deadline: Option<u64>,
+}
+
+/// Identifica un pedido entre robots y lider. Los ids de pedido solo son unicos
+/// dentro de cada interfaz, asi que van junto al id de la interfaz. En los
+/// mensajes se escribe `interfaz/pedido`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct OrderKey {
+    pub interface: usize,
+    pub order: usize,
+}
+
+impl OrderKey {
+    pub fn new(interface: usize, order: usize) -> OrderKey {
+        OrderKey { interface, order }
+    }
+}
+
+impl fmt::Display for OrderKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.interface, self.order)
+    }
+}
+
+impl FromStr for OrderKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OrderKey, String> {
+        let (interface, order) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid order key {}", s))?;
+        match (interface.parse(), order.parse()) {
+            (Ok(interface), Ok(order)) => Ok(OrderKey::new(interface, order)),
+            _ => Err(format!("Invalid order key {}", s)),
+        }
+    }
+}
+
+/// Hora actual en milisegundos desde UNIX_EPOCH, la misma escala que
+/// `DTO::deadline`.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 fn vec_to_json(products: &[String]) -> Vec<serde_json::Value> {
@@ -27,7 +85,20 @@ impl DTO {
             "size_order": self.size_order,
             "cash_card": self.cash_card,
             "total_amount": self.total_amount,
+            "owner": self.owner,
+            "takeover": self.takeover,
+            "deadline": self.deadline,
         });
         serialized.to_string()
     }
+
+    pub fn key(&self) -> OrderKey {
+        OrderKey::new(self.id_interface, self.id_order)
+    }
+
+    /// Si ya paso el plazo del pedido, no tiene sentido prepararlo.
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= now_millis())
+    }
 }
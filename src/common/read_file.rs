@@ -1,25 +1,500 @@
+use crate::common::order_entry::{validate_flavours, validate_size};
 use crate::structures::order::Order;
-use crate::structures::record::RecordList;
+use crate::structures::record::Record;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{self};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 
-pub fn read_file(path: &str) -> HashMap<usize, Order> {
-    let reader = fs::read_to_string(path).expect("Failed to read file");
-    let mut result = HashMap::new();
-    let order_list: RecordList = serde_json::from_str(&reader).expect("Unable to parse JSON");
+// Separador de sabores dentro de la columna `ice_creams` de un CSV
+const CSV_FLAVOUR_SEPARATOR: char = '|';
 
-    // Itera sobre los pedidos
-    for record in order_list.get_records() {
-        let order = Order::new(
-            record.get_id(),
-            record.get_client_id(),
-            record.get_ice_creams(),
-            0,
-            record.get_size_order(),
-            record.get_cash_card(),
-        );
-        // let parse_id_terminal = record.get_id().to_string() + "_" + &id_t.to_string();
-        result.insert(record.get_id(), order);
+/// Formatos de archivo de pedidos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderFormat {
+    /// `{"orders": [...]}`
+    Json,
+    /// Una fila por pedido con encabezado
+    /// `id,client_id,ice_creams,size_order,cash_card` y los sabores separados
+    /// por `|`.
+    Csv,
+    /// `orders:` con la misma forma que el JSON.
+    Yaml,
+}
+
+impl OrderFormat {
+    /// Formato segun la extension del archivo.
+    pub fn from_path(path: &str) -> Option<OrderFormat> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        extension.parse().ok()
+    }
+}
+
+impl FromStr for OrderFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(OrderFormat::Json),
+            "csv" => Ok(OrderFormat::Csv),
+            "yaml" | "yml" => Ok(OrderFormat::Yaml),
+            other => Err(format!("Unknown order file format {}", other)),
+        }
+    }
+}
+
+/// Registro del archivo que no se puede procesar.
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidRecord {
+    pub file: String,
+    /// Posicion del registro en el archivo, empezando en 0.
+    pub index: usize,
+    /// Id del registro, si se pudo leer.
+    pub id: Option<usize>,
+    pub reason: String,
+}
+
+/// Pedidos validos de un archivo, en el orden en que aparecen, y los registros
+/// que se descartaron.
+#[derive(Debug, Default)]
+pub struct OrderFile {
+    pub orders: Vec<Order>,
+    pub invalid: Vec<InvalidRecord>,
+}
+
+// Cada registro se lee por separado para que uno mal formado no invalide el
+// archivo entero
+#[derive(Deserialize)]
+struct RawRecordList<V> {
+    orders: Vec<V>,
+}
+
+// Fila de un CSV: los sabores vienen en una sola columna
+#[derive(Deserialize)]
+struct CsvRecord {
+    id: usize,
+    client_id: usize,
+    ice_creams: String,
+    size_order: f32,
+    cash_card: usize,
+}
+
+impl From<CsvRecord> for Record {
+    fn from(row: CsvRecord) -> Record {
+        Record::new(
+            row.id,
+            row.client_id,
+            row.ice_creams
+                .split(CSV_FLAVOUR_SEPARATOR)
+                .map(|flavour| flavour.trim().to_string())
+                .collect(),
+            row.size_order,
+            row.cash_card,
+        )
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Lee un archivo de pedidos en el formato indicado o, si no se indica, en el
+/// que corresponde a su extension (JSON si no se reconoce). Falla solo si no
+/// se puede leer o no tiene la forma esperada; los registros invalidos se
+/// informan con su posicion y el motivo y se siguen procesando los demas.
+pub fn read_file(path: &str, format: Option<OrderFormat>) -> io::Result<OrderFile> {
+    let reader = fs::read_to_string(path)?;
+    let format = format
+        .or_else(|| OrderFormat::from_path(path))
+        .unwrap_or(OrderFormat::Json);
+    let records: Vec<Result<Record, String>> = match format {
+        OrderFormat::Json => {
+            let list: RawRecordList<serde_json::Value> =
+                serde_json::from_str(&reader).map_err(invalid_data)?;
+            list.orders
+                .into_iter()
+                .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+                .collect()
+        }
+        OrderFormat::Yaml => {
+            let list: RawRecordList<serde_yaml::Value> =
+                serde_yaml::from_str(&reader).map_err(invalid_data)?;
+            list.orders
+                .into_iter()
+                .map(|value| serde_yaml::from_value(value).map_err(|e| e.to_string()))
+                .collect()
+        }
+        OrderFormat::Csv => {
+            let mut csv = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(reader.as_bytes());
+            csv.deserialize::<CsvRecord>()
+                .map(|row| row.map(Record::from).map_err(|e| e.to_string()))
+                .collect()
+        }
+    };
+    Ok(validate_records(path, records))
+}
+
+/// Valida registros ya leidos: sabores conocidos, de 1 a 3 por pedido, tamaño
+/// valido e ids sin repetir. De un id repetido se queda con el primero.
+pub fn validate_records<I>(file: &str, records: I) -> OrderFile
+where
+    I: IntoIterator<Item = Result<Record, String>>,
+{
+    let mut result = OrderFile::default();
+    let mut seen: HashMap<usize, usize> = HashMap::new();
+    for (index, record) in records.into_iter().enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(reason) => {
+                result.invalid.push(InvalidRecord {
+                    file: file.to_string(),
+                    index,
+                    id: None,
+                    reason,
+                });
+                continue;
+            }
+        };
+        let id = record.get_id();
+        let checked = match seen.get(&id) {
+            Some(first) => Err(format!("duplicate id {} (first at index {})", id, first)),
+            None => validate_record(&record),
+        };
+        match checked {
+            Ok(order) => {
+                seen.insert(id, index);
+                result.orders.push(order);
+            }
+            Err(reason) => result.invalid.push(InvalidRecord {
+                file: file.to_string(),
+                index,
+                id: Some(id),
+                reason,
+            }),
+        }
     }
     result
 }
+
+fn validate_record(record: &Record) -> Result<Order, String> {
+    let flavours = validate_flavours(&record.get_ice_creams())?;
+    let size = validate_size(record.get_size_order())?;
+    Ok(Order::new(
+        record.get_id(),
+        record.get_client_id(),
+        flavours.iter().map(|flavour| flavour.to_string()).collect(),
+        0,
+        size,
+        record.get_cash_card(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: usize, flavours: &[&str], size: f32) -> Result<Record, String> {
+        Ok(Record::new(
+            id,
+            7,
+            flavours.iter().map(|flavour| flavour.to_string()).collect(),
+            size,
+            1000,
+        ))
+    }
+
+    /// Escribe un archivo de pedidos temporal y devuelve su ruta.
+    fn write_orders(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read_orders(
+        name: &str,
+        content: &str,
+        format: Option<OrderFormat>,
+    ) -> io::Result<OrderFile> {
+        let path = write_orders(name, content);
+        let file = read_file(&path, format);
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    /// Lo que importa de cada pedido para comparar formatos.
+    fn summary(file: &OrderFile) -> Vec<(usize, usize, Vec<String>, f32, usize)> {
+        file.orders
+            .iter()
+            .map(|order| {
+                (
+                    order.id,
+                    order.id_client,
+                    order.products.clone(),
+                    order.amount,
+                    order.card_cash,
+                )
+            })
+            .collect()
+    }
+
+    fn ids(file: &OrderFile) -> Vec<usize> {
+        file.orders.iter().map(|order| order.id).collect()
+    }
+
+    /// Posicion, id y motivo de cada registro descartado.
+    fn invalid(file: &OrderFile) -> Vec<(usize, Option<usize>, &str)> {
+        file.invalid
+            .iter()
+            .map(|record| (record.index, record.id, record.reason.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn valid_records_keep_their_order_and_stock_names() {
+        let file = validate_records(
+            "orders.json",
+            vec![
+                record(3, &["chocolate", " Dulce de leche "], 0.5),
+                record(1, &["Frutilla"], 1.0),
+            ],
+        );
+        assert!(file.invalid.is_empty());
+        assert_eq!(ids(&file), vec![3, 1]);
+        assert_eq!(file.orders[0].products, vec!["Chocolate", "Dulce de Leche"]);
+        assert_eq!(file.orders[0].amount, 0.5);
+        assert_eq!(file.orders[0].card_cash, 1000);
+    }
+
+    #[test]
+    fn repeated_ids_keep_the_first_record() {
+        let file = validate_records(
+            "orders.json",
+            vec![
+                record(1, &["Chocolate"], 0.5),
+                record(2, &["Chocolate"], 0.5),
+                record(1, &["Vainilla"], 1.0),
+                record(2, &["Vainilla"], 1.0),
+            ],
+        );
+        assert_eq!(ids(&file), vec![1, 2]);
+        assert_eq!(file.orders[0].products, vec!["Chocolate"]);
+        assert_eq!(
+            invalid(&file),
+            vec![
+                (2, Some(1), "duplicate id 1 (first at index 0)"),
+                (3, Some(2), "duplicate id 2 (first at index 1)"),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_invalid_record_does_not_reserve_its_id() {
+        let file = validate_records(
+            "orders.json",
+            vec![record(1, &["Menta"], 0.5), record(1, &["Chocolate"], 0.5)],
+        );
+        assert_eq!(ids(&file), vec![1]);
+        assert_eq!(invalid(&file), vec![(0, Some(1), "unknown flavour Menta")]);
+    }
+
+    #[test]
+    fn reports_bad_sizes_and_flavours_at_their_index() {
+        let file = validate_records(
+            "orders.json",
+            vec![
+                record(1, &["Chocolate"], 0.3),
+                record(2, &["Chocolate"], 0.5),
+                record(3, &["Chocolate", "Menta"], 0.5),
+                record(4, &[], 0.5),
+                record(5, &["", " "], 0.5),
+                record(
+                    6,
+                    &["Chocolate", "Vainilla", "Frutilla", "Crema Americana"],
+                    1.0,
+                ),
+                record(7, &["Vainilla"], 0.0),
+            ],
+        );
+        assert_eq!(ids(&file), vec![2]);
+        assert_eq!(
+            invalid(&file),
+            vec![
+                (0, Some(1), "size must be one of [0.25, 0.5, 1.0]"),
+                (2, Some(3), "unknown flavour Menta"),
+                (3, Some(4), "between 1 and 3 flavours"),
+                (4, Some(5), "between 1 and 3 flavours"),
+                (5, Some(6), "between 1 and 3 flavours"),
+                (6, Some(7), "size must be one of [0.25, 0.5, 1.0]"),
+            ]
+        );
+        assert!(file
+            .invalid
+            .iter()
+            .all(|record| record.file == "orders.json"));
+    }
+
+    #[test]
+    fn unreadable_records_are_reported_without_id() {
+        let file = validate_records(
+            "orders.csv",
+            vec![
+                Err("missing field `size_order`".to_string()),
+                record(1, &["Chocolate"], 0.5),
+                Err("invalid type: string \"x\", expected usize".to_string()),
+            ],
+        );
+        assert_eq!(ids(&file), vec![1]);
+        assert_eq!(
+            invalid(&file),
+            vec![
+                (0, None, "missing field `size_order`"),
+                (2, None, "invalid type: string \"x\", expected usize"),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_same_orders_read_alike_in_every_format() {
+        let json = r#"{"orders": [
+            {"id": 1, "client_id": 4, "ice_creams": ["Chocolate", "Vainilla"], "size_order": 0.5, "cash_card": 1000},
+            {"id": 2, "client_id": 5, "ice_creams": ["Dulce de Leche"], "size_order": 0.25, "cash_card": 600}
+        ]}"#;
+        let csv = "id,client_id,ice_creams,size_order,cash_card\n\
+                   1,4,Chocolate|Vainilla,0.5,1000\n\
+                   2,5,Dulce de Leche,0.25,600\n";
+        let yaml = "orders:\n\
+                    \x20 - id: 1\n    client_id: 4\n    ice_creams: [Chocolate, Vainilla]\n    size_order: 0.5\n    cash_card: 1000\n\
+                    \x20 - id: 2\n    client_id: 5\n    ice_creams:\n      - Dulce de Leche\n    size_order: 0.25\n    cash_card: 600\n";
+
+        let from_json = read_orders("same.json", json, None).unwrap();
+        let from_csv = read_orders("same.csv", csv, None).unwrap();
+        let from_yaml = read_orders("same.yml", yaml, None).unwrap();
+        assert!(from_json.invalid.is_empty());
+        assert_eq!(summary(&from_json).len(), 2);
+        assert_eq!(summary(&from_csv), summary(&from_json));
+        assert_eq!(summary(&from_yaml), summary(&from_json));
+    }
+
+    #[test]
+    fn the_format_comes_from_the_option_or_the_extension() {
+        let csv = "id,client_id,ice_creams,size_order,cash_card\n1,4,Chocolate,0.5,1000\n";
+        assert_eq!(ids(&read_orders("ext.CSV", csv, None).unwrap()), vec![1]);
+        assert_eq!(
+            ids(&read_orders("ext.txt", csv, Some(OrderFormat::Csv)).unwrap()),
+            vec![1]
+        );
+        // Sin formato ni extension conocida se lee como JSON
+        assert!(read_orders("ext.txt", csv, None).is_err());
+    }
+
+    #[test]
+    fn csv_columns_are_matched_by_header() {
+        let csv = "cash_card, size_order ,id,ice_creams,client_id\n\
+                   1000, 0.5 ,1, chocolate | Frutilla ,4\n";
+        let file = read_orders("header.csv", csv, None).unwrap();
+        assert!(file.invalid.is_empty());
+        assert_eq!(
+            summary(&file),
+            vec![(
+                1,
+                4,
+                vec!["Chocolate".to_string(), "Frutilla".to_string()],
+                0.5,
+                1000
+            )]
+        );
+
+        // Sin encabezado la primera fila se toma como encabezado y las demas
+        // no tienen las columnas esperadas
+        let file = read_orders(
+            "no_header.csv",
+            "1,4,Chocolate,0.5,1000\n2,4,Chocolate,0.5,1000\n",
+            None,
+        )
+        .unwrap();
+        assert!(file.orders.is_empty());
+        assert_eq!(invalid(&file).len(), 1);
+        assert_eq!(file.invalid[0].index, 0);
+        assert_eq!(file.invalid[0].id, None);
+
+        // Solo el encabezado: un archivo sin pedidos
+        let file = read_orders(
+            "empty.csv",
+            "id,client_id,ice_creams,size_order,cash_card\n",
+            None,
+        )
+        .unwrap();
+        assert!(file.orders.is_empty() && file.invalid.is_empty());
+    }
+
+    #[test]
+    fn csv_flavour_lists_can_be_quoted_or_empty() {
+        let csv = "id,client_id,ice_creams,size_order,cash_card\n\
+                   1,4,\"Chocolate|Dulce de Leche\",0.5,1000\n\
+                   2,4,\"Crema Americana, extra\",0.5,1000\n\
+                   3,4,\"\",0.5,1000\n\
+                   4,4,,0.5,1000\n\
+                   5,4,Vainilla||,0.5,1000\n";
+        let file = read_orders("quoted.csv", csv, None).unwrap();
+        assert_eq!(ids(&file), vec![1, 5]);
+        assert_eq!(file.orders[0].products, vec!["Chocolate", "Dulce de Leche"]);
+        assert_eq!(file.orders[1].products, vec!["Vainilla"]);
+        assert_eq!(
+            invalid(&file),
+            vec![
+                (1, Some(2), "unknown flavour Crema Americana, extra"),
+                (2, Some(3), "between 1 and 3 flavours"),
+                (3, Some(4), "between 1 and 3 flavours"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_csv_rows_are_skipped() {
+        let csv = "id,client_id,ice_creams,size_order,cash_card\n\
+                   1,4,Chocolate,0.5,1000\n\
+                   x,4,Chocolate,0.5,1000\n\
+                   3,4,Chocolate\n\
+                   4,4,Chocolate,0.5,1000,extra\n\
+                   5,4,Chocolate,medio,1000\n\
+                   6,4,Chocolate,0.25,1000\n";
+        let file = read_orders("malformed.csv", csv, None).unwrap();
+        assert_eq!(ids(&file), vec![1, 6]);
+        let skipped: Vec<(usize, Option<usize>)> = file
+            .invalid
+            .iter()
+            .map(|record| (record.index, record.id))
+            .collect();
+        assert_eq!(skipped, vec![(1, None), (2, None), (3, None), (4, None)]);
+    }
+
+    #[test]
+    fn malformed_yaml_records_are_skipped() {
+        let yaml = "orders:\n\
+                    \x20 - {id: 1, client_id: 4, ice_creams: [Chocolate], size_order: 0.5, cash_card: 1000}\n\
+                    \x20 - {id: 2, client_id: 4, ice_creams: Chocolate, size_order: 0.5, cash_card: 1000}\n\
+                    \x20 - {id: 3, client_id: 4, size_order: 0.5, cash_card: 1000}\n\
+                    \x20 - {id: 4, client_id: 4, ice_creams: [], size_order: 0.5, cash_card: 1000}\n\
+                    \x20 - just text\n\
+                    \x20 - {id: 6, client_id: 4, ice_creams: [\"Frutilla\"], size_order: 1, cash_card: 1000}\n";
+        let file = read_orders("malformed.yaml", yaml, None).unwrap();
+        assert_eq!(ids(&file), vec![1, 6]);
+        let skipped: Vec<(usize, Option<usize>)> = file
+            .invalid
+            .iter()
+            .map(|record| (record.index, record.id))
+            .collect();
+        assert_eq!(skipped, vec![(1, None), (2, None), (3, Some(4)), (4, None)]);
+
+        // Sin la lista de pedidos no hay nada que leer
+        assert!(read_orders("no_orders.yaml", "- id: 1\n", None).is_err());
+        assert!(read_orders("broken.yaml", "orders: [", None).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use crate::common::log::{LogLevel, Logger};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Direccion local en el puerto `base + id`, o un error si se pasa de 65535.
+pub fn local_addr(base: usize, id: usize) -> Result<SocketAddr, String> {
+    base.checked_add(id)
+        .and_then(|port| u16::try_from(port).ok())
+        .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+        .ok_or_else(|| format!("Id {} out of range for port {}+id", id, base))
+}
+
+/// Convierte un socket ya bindeado en uno de tokio, para leerlo con `await`
+/// sin bloquear el arbiter del actor. Debe llamarse dentro del runtime.
+pub fn into_async(socket: std::net::UdpSocket) -> io::Result<Arc<UdpSocket>> {
+    socket.set_nonblocking(true)?;
+    Ok(Arc::new(UdpSocket::from_std(socket)?))
+}
+
+/// Envia un mensaje sin bloquear. Si el buffer del socket esta lleno el envio
+/// se completa en una tarea aparte en lugar de frenar al actor.
+pub fn send_message(socket: &Arc<UdpSocket>, message: &str, addr: SocketAddr) -> io::Result<usize> {
+    match socket.try_send_to(message.as_bytes(), addr) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            let socket = Arc::clone(socket);
+            let message = message.to_string();
+            let size = message.len();
+            actix::spawn(async move {
+                if let Err(e) = socket.send_to(message.as_bytes(), addr).await {
+                    Logger.log(
+                        LogLevel::Error,
+                        format!("Error sending message to {}: {}", addr, e).as_str(),
+                    );
+                }
+            });
+            Ok(size)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_addr_rejects_ports_that_do_not_fit() {
+        assert_eq!(local_addr(6000, 7).unwrap().port(), 6007);
+        assert_eq!(local_addr(6000, 59535).unwrap().port(), 65535);
+        assert!(local_addr(6000, 59536).is_err());
+        assert!(local_addr(6000, usize::MAX).is_err());
+    }
+}
@@ -1,4 +1,4 @@
 pub mod ack;
-pub mod prices_ice_creams;
+pub mod flavours;
 pub mod status_order;
 pub mod status_payment;
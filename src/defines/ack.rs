@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -6,6 +7,7 @@ pub struct Ack {
     msg: String,
     type_msg: String,
     num_tries: usize,
+    fallbacks: VecDeque<SocketAddr>,
 }
 
 impl Ack {
@@ -15,13 +17,33 @@ impl Ack {
             msg,
             type_msg,
             num_tries: 0,
+            fallbacks: VecDeque::new(),
         }
     }
 
+    /// Direcciones a las que pasar el mensaje, en orden, si no responde `addr`.
+    pub fn with_fallbacks(mut self, fallbacks: Vec<SocketAddr>) -> Self {
+        self.fallbacks = fallbacks.into();
+        self
+    }
+
+    pub fn next_fallback(&mut self) -> Option<SocketAddr> {
+        self.fallbacks.pop_front()
+    }
+
+    pub fn get_fallbacks(&self) -> Vec<SocketAddr> {
+        self.fallbacks.iter().copied().collect()
+    }
+
     pub fn increment_tries(&mut self) {
         self.num_tries += 1;
     }
 
+    pub fn redirect(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+        self.num_tries = 0;
+    }
+
     pub fn get_num_tries(&self) -> usize {
         self.num_tries
     }
@@ -0,0 +1,16 @@
+/// Sabores que hay en stock.
+pub const FLAVOURS: [&str; 5] = [
+    "Chocolate",
+    "Vainilla",
+    "Crema Americana",
+    "Dulce de Leche",
+    "Frutilla",
+];
+
+/// Busca un sabor sin importar mayusculas y devuelve su nombre en el stock.
+pub fn find_flavour(name: &str) -> Option<&'static str> {
+    FLAVOURS
+        .iter()
+        .find(|flavour| flavour.eq_ignore_ascii_case(name.trim()))
+        .copied()
+}
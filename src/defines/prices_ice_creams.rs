@@ -1,9 +0,0 @@
-// use std::collections::HashMap;
-
-// fn create_price_map() -> HashMap<String, i32> {
-//     let mut m: HashMap<String, i32> = HashMap::new();
-//     m.insert("1Kg".to_string(), 1500);
-//     m.insert("1/2Kg".to_string(), 1000);
-//     m.insert("1/4Kg".to_string(), 700);
-//     m
-// }
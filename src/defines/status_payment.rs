@@ -1,6 +1,20 @@
-#[derive(Debug)]
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum StatusPayment {
     Paid,
     Unpaid,
     Pending,
 }
+
+impl StatusPayment {
+    /// Estado del pago despues de pedir el cobro (si se completo el pedido) o
+    /// la anulacion.
+    pub fn settled(completed: bool) -> StatusPayment {
+        if completed {
+            StatusPayment::Paid
+        } else {
+            StatusPayment::Unpaid
+        }
+    }
+}
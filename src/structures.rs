@@ -1,9 +1,17 @@
 pub mod ack_manager;
+pub mod election;
 pub mod gateway;
 pub mod handle_connection;
 pub mod ice_cream;
 pub mod interface;
+pub mod interface_config;
 pub mod leader_order_processing;
 pub mod order;
+pub mod order_journal;
+pub mod order_report;
+pub mod pricing;
+pub mod raft;
 pub mod record;
 pub mod robot;
+pub mod robot_config;
+pub mod watch_state;
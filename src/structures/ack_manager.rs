@@ -1,10 +1,13 @@
 use crate::common::log::{LogLevel, Logger};
 use crate::defines::ack::Ack;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::sync::{Arc, Condvar};
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
 
 #[derive(Debug, Clone)]
 struct TimedItem {
@@ -24,7 +27,9 @@ impl TimedItem {
 pub struct AckManager {
     acks: Arc<Mutex<Vec<TimedItem>>>,
     sender: Arc<UdpSocket>,
+    runtime: Handle,
     condvar: Arc<Condvar>,
+    running: Arc<AtomicBool>,
 }
 
 impl Clone for AckManager {
@@ -32,17 +37,23 @@ impl Clone for AckManager {
         AckManager {
             acks: Arc::clone(&self.acks),
             sender: Arc::clone(&self.sender),
+            runtime: self.runtime.clone(),
             condvar: Arc::clone(&self.condvar),
+            running: Arc::clone(&self.running),
         }
     }
 }
 
 impl AckManager {
-    pub fn new(socket: UdpSocket) -> Self {
+    /// Reenvia por el mismo socket del actor dueño. Debe llamarse dentro del
+    /// runtime: los envios se hacen en el, porque el socket es no bloqueante.
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
         let ret = AckManager {
             acks: Arc::new(Mutex::new(Vec::new())),
-            sender: Arc::new(socket),
+            sender: socket,
+            runtime: Handle::current(),
             condvar: Arc::new(Condvar::new()),
+            running: Arc::new(AtomicBool::new(true)),
         };
         let mut clone = ret.clone();
         thread::spawn(move || clone.start());
@@ -50,7 +61,7 @@ impl AckManager {
     }
 
     fn start(&mut self) {
-        loop {
+        while self.running.load(Ordering::SeqCst) {
             self.wait_acks();
             thread::sleep(Duration::from_secs(1));
             // Logger.log(LogLevel::Error, "Tengo ack!!!!!!!");
@@ -60,22 +71,19 @@ impl AckManager {
             while i < items.len() {
                 if items[i].expiration <= now {
                     if items[i].item_type.get_num_tries() < 3 {
-                        self.sender
-                            .send_to(
-                                &items[i].item_type.get_msg().as_bytes(),
-                                &items[i].item_type.get_addr(),
-                            )
-                            .unwrap();
+                        self.send(items[i].item_type.get_msg(), items[i].item_type.get_addr());
                         items[i].expiration = Instant::now() + Duration::from_secs(1);
                         items[i].item_type.increment_tries();
                     } else {
                         let ack = items[i].item_type.clone();
-                        self.resolve_resilience(&ack);
                         Logger.log(
                             LogLevel::AckInfo,
                             format!("Se remueve un ACK {:?}", ack).as_str(),
                         );
                         let _ = items.remove(i);
+                        if let Some(next) = self.resolve_resilience(&ack) {
+                            items.push(TimedItem::new(next, Duration::from_secs(1)));
+                        }
                         drop(items);
                         break;
                     }
@@ -86,11 +94,28 @@ impl AckManager {
         }
     }
 
+    /// Manda el mensaje desde el runtime del actor, que espera a que el socket
+    /// se pueda escribir. Este thread no puede esperarlo: con el buffer lleno
+    /// el envio fallaria y el reintento se perderia.
+    fn send(&self, msg: String, addr: SocketAddr) {
+        let socket = Arc::clone(&self.sender);
+        self.runtime.spawn(async move {
+            if let Err(e) = socket.send_to(msg.as_bytes(), addr).await {
+                Logger.log(
+                    LogLevel::AckInfo,
+                    format!("Error resending {} to {}: {}", msg, addr, e).as_str(),
+                );
+            }
+        });
+    }
+
     fn wait_acks(&self) {
         let lock = self.acks.lock().unwrap();
         let _condvar_lock = self
             .condvar
-            .wait_while(lock, |lock| lock.is_empty())
+            .wait_while(lock, |lock| {
+                lock.is_empty() && self.running.load(Ordering::SeqCst)
+            })
             .unwrap();
     }
 
@@ -100,6 +125,29 @@ impl AckManager {
         self.condvar.notify_all();
     }
 
+    /// Descarta los acks pendientes y termina el thread de reenvios, soltando
+    /// su referencia al socket.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.acks.lock().unwrap().clear();
+        self.condvar.notify_all();
+    }
+
+    /// Redirige a `new_addr` los mensajes pendientes enviados a `old_addr`
+    /// cuyo tipo empiece con alguno de `types`, y los reenvia de inmediato.
+    pub fn redirect(&mut self, old_addr: SocketAddr, new_addr: SocketAddr, types: &[&str]) {
+        let mut acks = self.acks.lock().unwrap();
+        for item in acks.iter_mut() {
+            let ack = &mut item.item_type;
+            if ack.get_addr() == old_addr && types.iter().any(|t| ack.get_type_msg().starts_with(t))
+            {
+                ack.redirect(new_addr);
+                item.expiration = Instant::now();
+            }
+        }
+        self.condvar.notify_all();
+    }
+
     pub fn remove(&mut self, ack: String, addr: SocketAddr) {
         let mut acks = self.acks.lock().unwrap();
         acks.retain(|x| !x.item_type.is_equal(addr, ack.clone()));
@@ -107,35 +155,133 @@ impl AckManager {
         self.condvar.notify_all();
     }
 
-    fn resolve_resilience(&self, ack: &Ack) {
+    /// Que hacer con un mensaje que se dejo de reintentar. Devuelve el
+    /// mensaje a seguir reintentando en su lugar, si hay.
+    fn resolve_resilience(&self, ack: &Ack) -> Option<Ack> {
         match ack.get_type_msg().as_str() {
-            "Result_Interface" => self.interface_resilience(ack),
+            t if t.starts_with("Result_Interface") || t.starts_with("Resilience") => {
+                self.interface_resilience(ack)
+            }
             _ => {
                 // Handle other cases here
+                None
             }
         }
     }
 
-    fn interface_resilience(&self, ack: &Ack) {
-        let addr = format!("{}", ack.get_addr());
-        let id_str = &addr[(addr.len() - 2)..];
-        let id_interface: usize = id_str.parse().unwrap();
-        let msg_resilience: String = ack.get_msg()[6..].parse().unwrap();
+    /// La interfaz del pedido no confirmo el resultado: se le pasa como
+    /// `Resilience:` a la siguiente interfaz de su orden de reemplazo, que
+    /// queda a cargo del pedido si confirma.
+    fn interface_resilience(&self, ack: &Ack) -> Option<Ack> {
+        let msg = ack.get_msg();
+        let result = msg
+            .strip_prefix("Robot:")
+            .or_else(|| msg.strip_prefix("Resilience:"))?;
+        let id_order = result.split(',').next()?;
+        // En el primer salto se agrega la interfaz duena del pedido, porque
+        // los ids de pedido solo son unicos dentro de cada interfaz
+        let result = match msg.starts_with("Robot:") {
+            true => format!("{},{}", result, ack.get_addr()),
+            false => result.to_string(),
+        };
+        let mut ack = ack.clone();
+        let Some(next) = ack.next_fallback() else {
+            Logger.log(
+                LogLevel::Error,
+                format!("No interface took over order {}", id_order).as_str(),
+            );
+            return None;
+        };
+        Logger.log(
+            LogLevel::AckInfo,
+            format!("Order {} handed over to interface {}", id_order, next).as_str(),
+        );
+        let msg_resilience = format!("Resilience:{}", result);
+        self.send(msg_resilience.clone(), next);
+        Some(
+            Ack::new(next, msg_resilience, format!("Resilience:{}", id_order))
+                .with_fallbacks(ack.get_fallbacks()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::udp;
+    use std::collections::HashSet;
+
+    const ACKS: usize = 50;
+    const MESSAGES: usize = 200;
+
+    async fn bind() -> Arc<UdpSocket> {
+        udp::into_async(std::net::UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap()
+    }
 
-        let addr_next = format!("127.0.0.1:{}", 9000 + id_interface + 1);
-        //    Logger.log(LogLevel::Error, format!("Se envia a la siguiente interfaz: {:?}", addr_next).as_str());
-        if let Some(addr_n) = addr_next.to_socket_addrs().unwrap().next() {
-            let _ = self
-                .sender
-                .send_to(format!("Resilience:{}", msg_resilience).as_bytes(), addr_n);
+    /// Lee lo que llegue hasta que se cumpla `done` o pase `limit`.
+    async fn receive_until<F>(socket: &UdpSocket, limit: Duration, mut done: F) -> Vec<String>
+    where
+        F: FnMut(&[String]) -> bool,
+    {
+        let start = Instant::now();
+        let mut received = Vec::new();
+        let mut buffer = [0; 1024];
+        while !done(&received) && start.elapsed() < limit {
+            let recv =
+                tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buffer));
+            if let Ok(Ok((size, _))) = recv.await {
+                received.push(String::from_utf8_lossy(&buffer[..size]).to_string());
+            }
         }
+        received
+    }
 
-        let addr_prev = format!("127.0.0.1:{}", 9000 + id_interface - 1);
-        // Logger.log(LogLevel::Error, format!("Se envia a la interfaz anterior: {:?}", addr_prev).as_str());
-        if let Some(addr_p) = addr_prev.to_socket_addrs().unwrap().next() {
-            let _ = self
-                .sender
-                .send_to(format!("Resilience:{}", msg_resilience).as_bytes(), addr_p);
+    #[actix_rt::test]
+    async fn resends_while_the_actor_uses_the_same_socket() {
+        let socket = bind().await;
+        let receiver = bind().await;
+        let to = receiver.local_addr().unwrap();
+        let mut ack_manager = AckManager::new(Arc::clone(&socket));
+        for i in 0..ACKS {
+            let msg = format!("Work:{}", i);
+            ack_manager.add(Ack::new(to, msg.clone(), msg), Duration::ZERO);
         }
+        let actor_socket = Arc::clone(&socket);
+        actix::spawn(async move {
+            for i in 0..MESSAGES {
+                udp::send_message(&actor_socket, &format!("Progress:{}", i), to).unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let expected = |received: &[String]| {
+            let distinct: HashSet<&String> = received.iter().collect();
+            distinct.len() == ACKS + MESSAGES
+        };
+        let received = receive_until(&receiver, Duration::from_secs(5), expected).await;
+        ack_manager.stop();
+        assert!(expected(&received), "received {} messages", received.len());
+    }
+
+    #[actix_rt::test]
+    async fn hands_an_unacked_result_to_the_next_interface() {
+        let owner = bind().await;
+        let peer = bind().await;
+        let owner_addr = owner.local_addr().unwrap();
+        let mut ack_manager = AckManager::new(bind().await);
+        ack_manager.add(
+            Ack::new(
+                owner_addr,
+                "Robot:5,true".to_string(),
+                "Result_Interface:5".to_string(),
+            )
+            .with_fallbacks(vec![peer.local_addr().unwrap()]),
+            Duration::ZERO,
+        );
+        let received = receive_until(&peer, Duration::from_secs(10), |received| {
+            !received.is_empty()
+        })
+        .await;
+        ack_manager.stop();
+        assert_eq!(received, vec![format!("Resilience:5,true,{}", owner_addr)]);
     }
 }
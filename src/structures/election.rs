@@ -0,0 +1,418 @@
+use std::str::FromStr;
+
+/// Candidato en una eleccion. Gana el mayor: primero el de estado mas nuevo
+/// (solo si se configuro preferirlo), despues el de mayor prioridad y por
+/// ultimo el de mayor id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candidate {
+    pub freshness: u64,
+    pub priority: u32,
+    pub id: usize,
+}
+
+impl Candidate {
+    pub fn new(id: usize, priority: u32, freshness: u64) -> Candidate {
+        Candidate {
+            freshness,
+            priority,
+            id,
+        }
+    }
+
+    /// Parsea un candidato con el formato `id,prioridad,estado`.
+    pub fn parse(content: &str) -> Option<Candidate> {
+        let mut fields = content.split(',');
+        let id = fields.next()?.parse().ok()?;
+        let priority = fields.next()?.parse().ok()?;
+        let freshness = fields.next()?.parse().ok()?;
+        Some(Candidate::new(id, priority, freshness))
+    }
+
+    pub fn serialize(&self) -> String {
+        format!("{},{},{}", self.id, self.priority, self.freshness)
+    }
+}
+
+/// Algoritmo con el que los robots eligen un lider nuevo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElectionAlgorithm {
+    Ring,
+    Bully,
+}
+
+impl FromStr for ElectionAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ring" => Ok(ElectionAlgorithm::Ring),
+            "bully" => Ok(ElectionAlgorithm::Bully),
+            other => Err(format!("Unknown election algorithm {}", other)),
+        }
+    }
+}
+
+/// Lo que la eleccion le pide al robot que haga.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElectionAction {
+    /// Mandar `Election:epoca,candidato` al robot con ese id.
+    Send(usize, Candidate, u64),
+    /// Asumir como lider con una epoca mayor a la indicada.
+    Won(u64),
+    /// Llamar a `on_timeout` pasado el plazo de la eleccion.
+    StartTimer,
+}
+
+/// Estado de la eleccion de un robot. No hace I/O: devuelve las acciones que
+/// el robot tiene que ejecutar, asi los dos algoritmos se pueden probar sin
+/// red.
+///
+/// - Anillo: el mejor candidato da la vuelta pasando de un robot al
+///   siguiente; gana cuando le vuelve su propia candidatura.
+/// - Bully: el candidato se le manda a todos. Quien es mejor contesta con su
+///   propia candidatura y le gana; si en el plazo nadie mejor contesto, gana.
+#[derive(Debug, Clone)]
+pub struct ElectionState {
+    algorithm: ElectionAlgorithm,
+    electing: bool,
+    outranked: bool,
+    epoch: u64,
+}
+
+impl ElectionState {
+    pub fn new(algorithm: ElectionAlgorithm) -> ElectionState {
+        ElectionState {
+            algorithm,
+            electing: false,
+            outranked: false,
+            epoch: 0,
+        }
+    }
+
+    pub fn is_electing(&self) -> bool {
+        self.electing
+    }
+
+    /// Empieza una eleccion proponiendo a `own`. `peers` son los ids de los
+    /// demas miembros.
+    pub fn start(&mut self, own: Candidate, epoch: u64, peers: &[usize]) -> Vec<ElectionAction> {
+        if self.electing {
+            return Vec::new();
+        }
+        self.electing = true;
+        self.outranked = false;
+        self.epoch = self.epoch.max(epoch);
+        self.propose(own, own, peers)
+    }
+
+    /// Procesa la candidatura que llego de otro robot.
+    pub fn on_election(
+        &mut self,
+        own: Candidate,
+        candidate: Candidate,
+        epoch: u64,
+        peers: &[usize],
+    ) -> Vec<ElectionAction> {
+        self.epoch = self.epoch.max(epoch);
+        match self.algorithm {
+            ElectionAlgorithm::Ring => {
+                if candidate.id == own.id {
+                    self.electing = false;
+                    return vec![ElectionAction::Won(self.epoch)];
+                }
+                if candidate > own {
+                    self.electing = true;
+                    self.propose(own, candidate, peers)
+                } else if !self.electing {
+                    self.electing = true;
+                    self.propose(own, own, peers)
+                } else {
+                    // Su propia candidatura, que es mejor, ya esta dando la vuelta
+                    Vec::new()
+                }
+            }
+            ElectionAlgorithm::Bully => {
+                if candidate > own {
+                    // Hay alguien mejor: se espera su Leader, y si no llega
+                    // se vuelve a intentar
+                    let waiting = self.electing;
+                    self.electing = true;
+                    self.outranked = true;
+                    if waiting {
+                        Vec::new()
+                    } else {
+                        vec![ElectionAction::StartTimer]
+                    }
+                } else if self.electing {
+                    // Le contesta con su candidatura, que es mejor
+                    vec![ElectionAction::Send(candidate.id, own, self.epoch)]
+                } else {
+                    self.electing = true;
+                    self.propose(own, own, peers)
+                }
+            }
+        }
+    }
+
+    /// Vencio el plazo de la eleccion (solo bully).
+    pub fn on_timeout(&mut self, own: Candidate, peers: &[usize]) -> Vec<ElectionAction> {
+        if !self.electing {
+            return Vec::new();
+        }
+        if self.outranked {
+            // El mejor candidato no se anuncio: se vuelve a empezar
+            self.outranked = false;
+            return self.propose(own, own, peers);
+        }
+        self.electing = false;
+        vec![ElectionAction::Won(self.epoch)]
+    }
+
+    /// Se acepto un lider: termina la eleccion en curso.
+    pub fn on_leader(&mut self, epoch: u64) {
+        self.electing = false;
+        self.outranked = false;
+        self.epoch = self.epoch.max(epoch);
+    }
+
+    fn propose(
+        &self,
+        own: Candidate,
+        candidate: Candidate,
+        peers: &[usize],
+    ) -> Vec<ElectionAction> {
+        match self.algorithm {
+            ElectionAlgorithm::Ring => {
+                // El siguiente del anillo es el de id inmediato mayor, o el de
+                // menor id si ya no hay mayores
+                let next = peers
+                    .iter()
+                    .filter(|id| **id != own.id)
+                    .min_by_key(|id| (**id < own.id, **id));
+                match next {
+                    Some(next) => vec![ElectionAction::Send(*next, candidate, self.epoch)],
+                    None => vec![ElectionAction::Won(self.epoch)],
+                }
+            }
+            ElectionAlgorithm::Bully => {
+                let mut actions: Vec<ElectionAction> = peers
+                    .iter()
+                    .filter(|id| **id != own.id)
+                    .map(|id| ElectionAction::Send(*id, candidate, self.epoch))
+                    .collect();
+                actions.push(ElectionAction::StartTimer);
+                actions
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+    /// Cluster simulado: los mensajes se entregan por rondas y los robots
+    /// caidos no reciben ni mandan nada. Los plazos vencen cuando una ronda
+    /// queda sin mensajes.
+    struct Cluster {
+        candidates: BTreeMap<usize, Candidate>,
+        states: BTreeMap<usize, ElectionState>,
+        members: BTreeMap<usize, Vec<usize>>,
+        crashed: BTreeSet<usize>,
+        queue: VecDeque<(usize, Candidate, u64)>,
+        timers: BTreeSet<usize>,
+        messages: usize,
+    }
+
+    /// Resultado de una eleccion: el ganador, las rondas y los mensajes que
+    /// hicieron falta.
+    #[derive(Debug)]
+    struct Outcome {
+        winner: Option<usize>,
+        rounds: usize,
+        messages: usize,
+    }
+
+    impl Cluster {
+        fn new(algorithm: ElectionAlgorithm, robots: &[(usize, u32)]) -> Cluster {
+            let ids: Vec<usize> = robots.iter().map(|(id, _)| *id).collect();
+            Cluster {
+                candidates: robots
+                    .iter()
+                    .map(|(id, priority)| (*id, Candidate::new(*id, *priority, 0)))
+                    .collect(),
+                states: ids
+                    .iter()
+                    .map(|id| (*id, ElectionState::new(algorithm)))
+                    .collect(),
+                members: ids
+                    .iter()
+                    .map(|id| (*id, ids.iter().copied().filter(|p| p != id).collect()))
+                    .collect(),
+                crashed: BTreeSet::new(),
+                queue: VecDeque::new(),
+                timers: BTreeSet::new(),
+                messages: 0,
+            }
+        }
+
+        /// Se cae un robot. Si `announced` es true los demas se enteran de la
+        /// baja y lo sacan de su lista de miembros.
+        fn crash(&mut self, id: usize, announced: bool) {
+            self.crashed.insert(id);
+            if announced {
+                for peers in self.members.values_mut() {
+                    peers.retain(|peer| *peer != id);
+                }
+            }
+        }
+
+        fn apply(&mut self, id: usize, actions: Vec<ElectionAction>) -> Option<usize> {
+            let mut winner = None;
+            for action in actions {
+                match action {
+                    ElectionAction::Send(to, candidate, epoch) => {
+                        self.messages += 1;
+                        if !self.crashed.contains(&to) {
+                            self.queue.push_back((to, candidate, epoch));
+                        }
+                    }
+                    ElectionAction::Won(_) => winner = Some(id),
+                    ElectionAction::StartTimer => {
+                        self.timers.insert(id);
+                    }
+                }
+            }
+            winner
+        }
+
+        fn elect(&mut self, initiators: &[usize], max_rounds: usize) -> Outcome {
+            let mut winner = None;
+            for id in initiators {
+                let actions = self.states.get_mut(id).unwrap().start(
+                    self.candidates[id],
+                    0,
+                    &self.members[id],
+                );
+                winner = winner.or(self.apply(*id, actions));
+            }
+            let mut rounds = 0;
+            while winner.is_none() && rounds < max_rounds {
+                rounds += 1;
+                if self.queue.is_empty() {
+                    let mut timers = std::mem::take(&mut self.timers);
+                    timers.retain(|id| !self.crashed.contains(id));
+                    for id in timers {
+                        let actions = self
+                            .states
+                            .get_mut(&id)
+                            .unwrap()
+                            .on_timeout(self.candidates[&id], &self.members[&id]);
+                        winner = winner.or(self.apply(id, actions));
+                    }
+                    continue;
+                }
+                for (to, candidate, epoch) in std::mem::take(&mut self.queue) {
+                    if self.crashed.contains(&to) {
+                        continue;
+                    }
+                    let actions = self.states.get_mut(&to).unwrap().on_election(
+                        self.candidates[&to],
+                        candidate,
+                        epoch,
+                        &self.members[&to],
+                    );
+                    winner = winner.or(self.apply(to, actions));
+                }
+            }
+            Outcome {
+                winner,
+                rounds,
+                messages: self.messages,
+            }
+        }
+    }
+
+    fn robots(n: usize) -> Vec<(usize, u32)> {
+        (1..=n).map(|id| (id, 0)).collect()
+    }
+
+    #[test]
+    fn candidates_rank_by_freshness_then_priority_then_id() {
+        let base = Candidate::new(1, 0, 0);
+        assert!(Candidate::new(2, 0, 0) > base);
+        assert!(Candidate::new(1, 5, 0) > Candidate::new(9, 0, 0));
+        assert!(Candidate::new(1, 0, 3) > Candidate::new(9, 5, 2));
+        assert_eq!(
+            Candidate::parse(&Candidate::new(4, 2, 7).serialize()),
+            Some(Candidate::new(4, 2, 7))
+        );
+    }
+
+    #[test]
+    fn both_elect_the_best_robot_after_an_announced_leader_crash() {
+        for algorithm in [ElectionAlgorithm::Ring, ElectionAlgorithm::Bully] {
+            let mut cluster = Cluster::new(algorithm, &robots(5));
+            cluster.crash(5, true);
+            let outcome = cluster.elect(&[2], 50);
+            assert_eq!(outcome.winner, Some(4), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn both_prefer_priority_over_id() {
+        for algorithm in [ElectionAlgorithm::Ring, ElectionAlgorithm::Bully] {
+            let mut cluster = Cluster::new(algorithm, &[(1, 0), (2, 7), (3, 0), (4, 0)]);
+            let outcome = cluster.elect(&[1, 3], 50);
+            assert_eq!(outcome.winner, Some(2), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn bully_converges_when_a_crash_was_not_announced() {
+        let mut cluster = Cluster::new(ElectionAlgorithm::Bully, &robots(5));
+        cluster.crash(5, false);
+        let outcome = cluster.elect(&[1], 50);
+        assert_eq!(outcome.winner, Some(4));
+    }
+
+    #[test]
+    fn ring_stalls_when_a_crash_was_not_announced() {
+        // El anillo necesita la lista de miembros al dia: el mensaje que va
+        // al robot caido se pierde y la eleccion no termina
+        let mut cluster = Cluster::new(ElectionAlgorithm::Ring, &robots(5));
+        cluster.crash(3, false);
+        let outcome = cluster.elect(&[2], 50);
+        assert_eq!(outcome.winner, None);
+    }
+
+    #[test]
+    fn bully_needs_fewer_rounds_and_more_messages_than_ring() {
+        let mut ring = Cluster::new(ElectionAlgorithm::Ring, &robots(8));
+        ring.crash(8, true);
+        let ring = ring.elect(&[1], 100);
+
+        let mut bully = Cluster::new(ElectionAlgorithm::Bully, &robots(8));
+        bully.crash(8, true);
+        let bully = bully.elect(&[1], 100);
+
+        assert_eq!(ring.winner, Some(7));
+        assert_eq!(bully.winner, Some(7));
+        // El anillo da la vuelta completa mas lo que tarda en llegar al mejor
+        assert!(ring.rounds >= 7, "{:?}", ring);
+        assert!(bully.rounds < ring.rounds, "{:?} {:?}", bully, ring);
+        assert!(bully.messages > ring.messages, "{:?} {:?}", bully, ring);
+    }
+
+    #[test]
+    fn bully_restarts_when_the_better_candidate_crashes_mid_election() {
+        let mut cluster = Cluster::new(ElectionAlgorithm::Bully, &robots(4));
+        cluster.elect(&[1], 1);
+        // El 4 ya se propuso y le gano a los demas, pero se cae antes de
+        // anunciarse como lider
+        cluster.crash(4, false);
+        let outcome = cluster.elect(&[], 50);
+        assert_eq!(outcome.winner, Some(3));
+    }
+}
@@ -3,12 +3,11 @@ use crate::common::protocol::DTO;
 use crate::structures::handle_connection::HandleConnection;
 use actix::prelude::*;
 use rand::Rng;
-use std::net::{ToSocketAddrs, UdpSocket};
+use std::net::{SocketAddr, UdpSocket};
 use tokio::io::{AsyncBufReadExt, BufReader};
 
 #[derive(Message)]
 #[rtype(result = "()")]
-
 struct Msg {
     pub content: String,
 }
@@ -28,28 +27,49 @@ impl GatewayPayment {
         GatewayPayment { logger: Logger }
     }
 
+    /// Procesa `Payment:id,resultado[,interfaz duena]`. La interfaz duena
+    /// viene cuando otra interfaz cobra un pedido adoptado.
     pub fn finish_order(&mut self, content: Vec<&str>) {
-        let id_order: usize = content[0].parse::<usize>().unwrap();
-        let result: bool = content[1].trim_matches('\n') == "true";
+        let Some(Ok(id_order)) = content.first().map(|id| id.parse::<usize>()) else {
+            Logger.log(
+                LogLevel::Error,
+                format!("Malformed payment line: {:?}", content).as_str(),
+            );
+            return;
+        };
+        let result: bool = content.get(1).map(|r| r.trim_matches('\n')) == Some("true");
+        let owner: Option<SocketAddr> =
+            match content.get(2).map(|owner| owner.trim_matches('\n').parse()) {
+                Some(Ok(owner)) => Some(owner),
+                Some(Err(_)) => {
+                    Logger.log(
+                        LogLevel::Error,
+                        format!("Malformed owner in payment line: {:?}", content).as_str(),
+                    );
+                    return;
+                }
+                None => None,
+            };
+        let order = match owner {
+            Some(owner) => format!("{} of {}", id_order, owner),
+            None => id_order.to_string(),
+        };
         if result {
             self.logger.log(
                 LogLevel::GatewayPayment,
-                format!("Order {} completed, payment done.", id_order).as_str(),
+                format!("Order {} completed, payment done.", order).as_str(),
             );
         } else {
             self.logger.log(
                 LogLevel::GatewayPayment,
-                format!("Order {} rejected, payment not done.", id_order).as_str(),
+                format!("Order {} rejected, payment not done.", order).as_str(),
             );
         }
     }
 
-    pub fn send_message_to_interface(&mut self, message: &str, id: usize) {
+    pub fn send_message_to_interface(&mut self, message: &str, addr: SocketAddr) {
         let socket = UdpSocket::bind(format!("127.0.0.1:{}", 8081)).unwrap(); // Puerto para enviar a la interfaz
-        let addr_interface = format!("127.0.0.1:{}", 9000 + id);
-        if let Some(socket_interface) = addr_interface.to_socket_addrs().unwrap().next() {
-            let _ = socket.send_to(message.as_bytes(), socket_interface);
-        }
+        let _ = socket.send_to(message.as_bytes(), addr);
     }
 
     pub fn process_order(&mut self, order: String) {
@@ -66,7 +86,7 @@ impl GatewayPayment {
                     );
                     self.send_message_to_interface(
                         format!("Payment:{},{}", false, dto.id_order).as_str(),
-                        dto.id_interface,
+                        dto.owner,
                     );
                 } else {
                     Logger.log(
@@ -75,7 +95,7 @@ impl GatewayPayment {
                     );
                     self.send_message_to_interface(
                         format!("Payment:{},{}", true, dto.id_order).as_str(),
-                        dto.id_interface,
+                        dto.owner,
                     );
                 }
             }
@@ -20,6 +20,10 @@ impl IceCreamContainer {
         }
     }
 
+    pub fn get_stock(&self) -> f64 {
+        *self.stock.lock().unwrap()
+    }
+
     pub fn use_stock(&mut self, amount: f64) -> bool {
         let mut stock = self.stock.lock().unwrap();
         let mut result = false;
@@ -1,20 +1,31 @@
 use crate::common::log::{LogLevel, Logger};
-use crate::common::protocol::DTO;
-use crate::common::read_file::read_file;
+use crate::common::protocol::{now_millis, DTO};
+use crate::common::read_file::{read_file, InvalidRecord, OrderFile, OrderFormat};
+use crate::common::udp;
 use crate::defines::ack::Ack;
+use crate::defines::status_payment::StatusPayment;
 use crate::structures::ack_manager::AckManager;
+use crate::structures::interface_config::{InterfaceConfig, OrderSource};
 use crate::structures::order::Order;
+use crate::structures::order_journal::{
+    JournalEntry, OrderJournal, Recovered, RecoveredAdoption, RecoveredOrder, Stage,
+};
+use crate::structures::order_report::{AdoptedOrder, InterfaceReport, OrderOutcome, OrderResult};
+use crate::structures::pricing::Pricing;
+use crate::structures::watch_state::WatchFolder;
 use actix::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::io;
-use std::io::Write;
-use std::net::TcpStream;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
 
 #[derive(Message)]
 #[rtype(result = "()")]
-
 struct Msg {
     pub content: String,
     addr: SocketAddr,
@@ -40,9 +51,58 @@ struct RobotMessage {
 struct ResilienceMessage {
     id: usize,
     result: bool,
+    owner: Option<SocketAddr>,
+    addr: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct LeaderMessage {
+    epoch: u64,
+    endpoint: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct NewLeaderMessage {
+    epoch: u64,
+    endpoint: SocketAddr,
     addr: SocketAddr,
 }
 
+/// Pedido cargado a mano que se manda al gateway como los del archivo.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubmitOrder {
+    pub order: Order,
+}
+
+/// Pide el estado de los pedidos cargados hasta el momento.
+#[derive(Message)]
+#[rtype(result = "InterfaceReport")]
+pub struct GetReport;
+
+/// Se corto la conexion con el gateway indicada.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct GatewayDisconnected {
+    connection: u64,
+}
+
+/// Resultado del intento de conexion con el gateway.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct GatewayConnected {
+    stream: io::Result<TcpStream>,
+}
+
+/// Ya salio por la conexion indicada la linea mas vieja que se le paso.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct GatewayWritten {
+    connection: u64,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 struct AckMessage {
@@ -52,62 +112,422 @@ struct AckMessage {
 
 pub struct Interface {
     id: usize,
-    file_path: String,
+    file: Option<OrderFile>,
+    watch: Option<WatchFolder>,
+    format: Option<OrderFormat>,
+    invalid: Vec<InvalidRecord>,
+    journal: OrderJournal,
+    recovered: Recovered,
+    addr: SocketAddr,
+    peers: Vec<SocketAddr>,
+    adopted: BTreeMap<(SocketAddr, usize), OrderResult>,
     logger: Logger,
-    socket: UdpSocket,
-    stream: TcpStream,
+    socket: Arc<UdpSocket>,
+    // Hilo que escribe en la conexion con el gateway, si hay conexion
+    writer: Option<mpsc::Sender<String>>,
+    connecting: bool,
+    // Numero de la conexion actual, para ignorar avisos de conexiones viejas
+    connection: u64,
+    outbox: VecDeque<GatewayLine>,
+    // Lineas que ya se le pasaron al hilo que escribe y no salieron todavia
+    sending: VecDeque<GatewayLine>,
+    authorizing: BTreeSet<usize>,
+    reconnect_delay: Duration,
+    next_reconnect: Instant,
     orders: HashMap<usize, Order>,
+    results: BTreeMap<usize, OrderResult>,
+    sla: Duration,
+    // Plazo de entrega de los pedidos aprobados sin resultado, en ms
+    deadlines: BTreeMap<usize, u64>,
+    pricing: Pricing,
+    robots: Vec<SocketAddr>,
+    leader: Option<SocketAddr>,
+    leader_epoch: u64,
+    unacked: BTreeMap<usize, String>,
     ack_manager: AckManager,
 }
 
-impl Interface {
-    pub fn new(id: usize, file: String) -> io::Result<Interface> {
-        let socket = UdpSocket::bind(format!("127.0.0.1:{}", 9000 + id)).unwrap();
-        let socket_clone = socket.try_clone().unwrap();
+// Cada cuanto se vuelve a preguntar por el lider
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(1);
+// Cada cuanto se revisa la carpeta vigilada
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// Cada cuanto se revisan los plazos de entrega
+const DEADLINE_INTERVAL: Duration = Duration::from_secs(1);
+const GATEWAY_ADDR: &str = "127.0.0.1:8080";
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+// Espera entre intentos de reconexion: se duplica hasta el maximo
+const RECONNECT_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_MAX: Duration = Duration::from_secs(8);
 
-        let connection =
-            TcpStream::connect("127.0.0.1:8080").expect("Could not connect to gateway");
+/// Mensaje para el gateway. Mientras no hay conexion queda en la cola.
+#[derive(Debug)]
+enum GatewayLine {
+    Order {
+        id: usize,
+        dto: String,
+    },
+    Payment {
+        id: usize,
+        completed: bool,
+        /// Interfaz duena si el pedido es adoptado.
+        owner: Option<SocketAddr>,
+    },
+}
+
+impl GatewayLine {
+    fn text(&self) -> String {
+        match self {
+            GatewayLine::Order { dto, .. } => format!("Order:{}\n", dto),
+            // Payment:id,resultado[,interfaz duena]
+            GatewayLine::Payment {
+                id,
+                completed,
+                owner: Some(owner),
+            } => format!("Payment:{},{},{}\n", id, completed, owner),
+            GatewayLine::Payment {
+                id,
+                completed,
+                owner: None,
+            } => format!("Payment:{},{}\n", id, completed),
+        }
+    }
+}
+
+/// Arma la cola para una conexion nueva con el gateway. Lo que se le paso al
+/// hilo que escribe y no se confirmo vuelve al frente, en el mismo orden.
+/// Devuelve los pedidos que esperan autorizacion y no estan en la cola: ya
+/// salieron, pero el gateway pudo caerse sin procesarlos.
+fn requeue_for_reconnect(
+    outbox: &mut VecDeque<GatewayLine>,
+    sending: &mut VecDeque<GatewayLine>,
+    authorizing: &BTreeSet<usize>,
+) -> Vec<usize> {
+    while let Some(line) = sending.pop_back() {
+        outbox.push_front(line);
+    }
+    let queued: BTreeSet<usize> = outbox
+        .iter()
+        .filter_map(|line| match line {
+            GatewayLine::Order { id, .. } => Some(*id),
+            GatewayLine::Payment { .. } => None,
+        })
+        .collect();
+    authorizing.difference(&queued).copied().collect()
+}
+
+impl Interface {
+    pub fn new(config: InterfaceConfig) -> io::Result<Interface> {
+        let id = config.id;
+        // El archivo o la carpeta se abren antes de conectarse para fallar
+        // enseguida si no sirven
+        let (file, watch) = match &config.source {
+            OrderSource::File(path) => (Some(read_file(path, config.format)?), None),
+            OrderSource::Interactive => (None, None),
+            OrderSource::Watch(dir) => (None, Some(WatchFolder::new(dir.clone(), id)?)),
+        };
+        let (journal, recovered) = OrderJournal::open(&config.journal)?;
+        let socket = std::net::UdpSocket::bind(config.addr)?;
+        let addr = socket.local_addr()?;
+        let socket = udp::into_async(socket)?;
+        let ack_manager = AckManager::new(Arc::clone(&socket));
 
         Ok(Interface {
             id,
-            file_path: file,
+            file,
+            watch,
+            format: config.format,
+            invalid: Vec::new(),
+            journal,
+            recovered,
+            addr,
+            peers: config.peers,
+            adopted: BTreeMap::new(),
             logger: Logger,
             socket,
-            stream: connection,
+            writer: None,
+            connecting: false,
+            connection: 0,
+            outbox: VecDeque::new(),
+            sending: VecDeque::new(),
+            authorizing: BTreeSet::new(),
+            reconnect_delay: RECONNECT_MIN,
+            next_reconnect: Instant::now(),
             orders: HashMap::new(),
-            ack_manager: AckManager::new(socket_clone),
+            results: BTreeMap::new(),
+            sla: config.sla,
+            deadlines: BTreeMap::new(),
+            pricing: Pricing::new(config.pricing),
+            robots: config.robots,
+            leader: None,
+            leader_epoch: 0,
+            unacked: BTreeMap::new(),
+            ack_manager,
         })
     }
 
-    fn send_order_to_gateway(&mut self, message: &str) -> Result<(), std::io::Error> {
-        // Send the message
+    /// Intenta conectarse al gateway si ya paso la espera desde el ultimo
+    /// intento. La conexion se hace en otro hilo, que avisa el resultado con
+    /// `GatewayConnected`, para no frenar al actor.
+    fn connect_gateway(&mut self, ctx: &mut Context<Self>) {
+        if self.writer.is_some() || self.connecting || Instant::now() < self.next_reconnect {
+            return;
+        }
+        self.connecting = true;
+        let actor = ctx.address();
+        thread::spawn(move || {
+            let stream = GATEWAY_ADDR
+                .parse()
+                .map_err(io::Error::other)
+                .and_then(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT));
+            actor.do_send(GatewayConnected { stream });
+        });
+    }
 
-        self.stream.write_all(b"Order:")?; // Especifico que es una orden lo que envio DESP DESCOMENTAR CUANDO LO IMPLEMENTE
-        self.stream.write_all(message.as_bytes())?;
-        self.stream.write_all(b"\n")?; // Append newline delimiter
+    /// Al conectarse vuelve a pedir la autorizacion de los pedidos que no
+    /// tuvieron respuesta, porque el gateway pudo no recibirlos, y manda lo
+    /// que quedo en cola.
+    fn gateway_connected(&mut self, result: io::Result<TcpStream>, ctx: &mut Context<Self>) {
+        self.connecting = false;
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "[Interface {}] Could not connect to gateway, retrying in {:?}: {}",
+                        self.id, self.reconnect_delay, e
+                    )
+                    .as_str(),
+                );
+                self.next_reconnect = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay = (self.reconnect_delay * 2).min(RECONNECT_MAX);
+                return;
+            }
+        };
+        self.connection += 1;
+        self.watch_gateway(&stream, ctx);
+        self.writer = Some(self.write_gateway(stream, ctx));
+        self.reconnect_delay = RECONNECT_MIN;
+        self.logger.log(LogLevel::Info, "Connected to gateway");
 
-        Ok(())
+        let in_flight =
+            requeue_for_reconnect(&mut self.outbox, &mut self.sending, &self.authorizing);
+        for id in in_flight {
+            if let Some(order) = self.orders.get(&id) {
+                self.logger.log(
+                    LogLevel::Info,
+                    format!("Order {} had no answer from the gateway, resending", id).as_str(),
+                );
+                let dto = self.create_order(order).serialize();
+                self.outbox.push_front(GatewayLine::Order { id, dto });
+            }
+        }
+        self.flush_gateway();
     }
 
-    fn send_order_to_robot(&mut self, message: &str) -> Result<(), std::io::Error> {
-        // Send the message
-        let msg = format!("Order:{}", message);
-        let addr_interface = "127.0.0.1:5000";
-        if let Some(socket_interface) = addr_interface.to_socket_addrs().unwrap().next() {
-            let _ = self.socket.send_to(msg.as_bytes(), socket_interface);
-            self.ack_manager.add(
-                Ack::new(
-                    socket_interface,
-                    msg.clone().to_string(),
-                    "Order".to_string(),
-                ),
-                Duration::from_secs(5),
+    /// El gateway nunca escribe por TCP, asi que leer solo sirve para
+    /// enterarse enseguida de que cerro la conexion.
+    fn watch_gateway(&self, stream: &TcpStream, ctx: &mut Context<Self>) {
+        let Ok(mut reader) = stream.try_clone() else {
+            return;
+        };
+        let actor = ctx.address();
+        let connection = self.connection;
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            while let Ok(size) = reader.read(&mut buffer) {
+                if size == 0 {
+                    break;
+                }
+            }
+            actor.do_send(GatewayDisconnected { connection });
+        });
+    }
+
+    /// Escribe en la conexion desde otro hilo y avisa cada linea que sale
+    /// con `GatewayWritten`. Si falla una escritura avisa que se corto la
+    /// conexion y deja de escribir.
+    fn write_gateway(
+        &self,
+        mut stream: TcpStream,
+        ctx: &mut Context<Self>,
+    ) -> mpsc::Sender<String> {
+        let (writer, lines) = mpsc::channel::<String>();
+        let actor = ctx.address();
+        let connection = self.connection;
+        thread::spawn(move || {
+            for line in lines {
+                if let Err(e) = stream.write_all(line.as_bytes()) {
+                    Logger.log(
+                        LogLevel::Error,
+                        format!("Error sending message to gateway: {}", e).as_str(),
+                    );
+                    actor.do_send(GatewayDisconnected { connection });
+                    break;
+                }
+                actor.do_send(GatewayWritten { connection });
+            }
+            // Asi tambien termina el hilo de watch_gateway
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        writer
+    }
+
+    /// Lo que no se llego a escribir se vuelve a mandar en la proxima
+    /// conexion (ver `requeue_for_reconnect`).
+    fn gateway_lost(&mut self) {
+        if self.writer.take().is_some() {
+            self.logger.log(
+                LogLevel::Error,
+                format!(
+                    "[Interface {}] Lost connection to gateway, {} messages queued",
+                    self.id,
+                    self.sending.len() + self.outbox.len()
+                )
+                .as_str(),
             );
         }
+        self.next_reconnect = Instant::now() + self.reconnect_delay;
+    }
+
+    /// Encola el mensaje y manda todo lo que se pueda.
+    fn send_to_gateway(&mut self, line: GatewayLine) {
+        self.outbox.push_back(line);
+        self.flush_gateway();
+    }
+
+    /// Le pasa la cola al hilo que escribe, si hay conexion.
+    fn flush_gateway(&mut self) {
+        let Some(writer) = self.writer.clone() else {
+            return;
+        };
+        while let Some(line) = self.outbox.pop_front() {
+            if writer.send(line.text()).is_err() {
+                self.outbox.push_front(line);
+                self.gateway_lost();
+                return;
+            }
+            self.sending.push_back(line);
+        }
+    }
+
+    /// Una linea ya salio hacia el gateway: un pago recien ahi se da por
+    /// cobrado o anulado.
+    fn gateway_written(&mut self, connection: u64) {
+        if connection != self.connection {
+            return;
+        }
+        match self.sending.pop_front() {
+            Some(GatewayLine::Payment {
+                id,
+                completed,
+                owner,
+            }) => {
+                self.record(JournalEntry::Settled { id, owner });
+                let result = match owner {
+                    Some(owner) => self.adopted.get_mut(&(owner, id)),
+                    None => self.results.get_mut(&id),
+                };
+                if let Some(result) = result {
+                    result.payment = StatusPayment::settled(completed);
+                }
+            }
+            Some(_) => self.logger.log(LogLevel::Info, "Message sent to gateway"),
+            None => {}
+        }
+    }
+
+    /// Manda el pedido al lider. El pedido queda guardado hasta que el lider
+    /// lo confirme, asi se le puede volver a mandar si cambia el lider.
+    fn send_order_to_robot(
+        &mut self,
+        id_order: usize,
+        message: &str,
+    ) -> Result<(), std::io::Error> {
+        let msg = format!("Order:{}", message);
+        self.unacked.insert(id_order, msg.clone());
+        match self.leader {
+            Some(leader) => self.send_to_leader(id_order, msg, leader)?,
+            None => self.discover_leader(),
+        }
         Ok(())
     }
 
+    fn send_to_leader(
+        &mut self,
+        id_order: usize,
+        msg: String,
+        leader: SocketAddr,
+    ) -> io::Result<()> {
+        udp::send_message(&self.socket, msg.as_str(), leader)?;
+        self.ack_manager.add(
+            Ack::new(leader, msg, format!("Order:{}", id_order)),
+            Duration::from_secs(5),
+        );
+        Ok(())
+    }
+
+    /// Le pregunta a los robots conocidos quien es el lider.
+    fn discover_leader(&self) {
+        for robot in self.robots.iter() {
+            let _ = udp::send_message(&self.socket, "WhoIsLeader", *robot);
+        }
+    }
+
+    /// Sigue al lider informado si es de una epoca igual o mas nueva y le
+    /// reenvia todos los pedidos sin confirmar, incluso los que ya se habian
+    /// dejado de reintentar.
+    fn handle_leader(&mut self, msg: LeaderMessage) {
+        if msg.epoch < self.leader_epoch || self.leader == Some(msg.endpoint) {
+            return;
+        }
+        self.logger.log(
+            LogLevel::Info,
+            format!(
+                "[Interface {}] Leader at {} for epoch {}",
+                self.id, msg.endpoint, msg.epoch
+            )
+            .as_str(),
+        );
+        let old_leader = self.leader.replace(msg.endpoint);
+        self.leader_epoch = msg.epoch;
+        let unacked: Vec<(usize, String)> = self
+            .unacked
+            .iter()
+            .map(|(id, order)| (*id, order.clone()))
+            .collect();
+        for (id_order, order) in unacked {
+            if let Some(old_leader) = old_leader {
+                self.ack_manager
+                    .remove(format!("Order:{}", id_order), old_leader);
+            }
+            if let Err(e) = self.send_to_leader(id_order, order, msg.endpoint) {
+                self.logger.log(
+                    LogLevel::Error,
+                    format!("Error sending message to Leader Robots: {}", e).as_str(),
+                );
+            }
+        }
+    }
+
     fn handle_gateway(&mut self, msg: GatewayMessage) {
+        // Una respuesta repetida (por un pedido reenviado al reconectar) no
+        // vuelve a mandar el pedido
+        if !self.authorizing.remove(&msg.id) {
+            return;
+        }
+        if msg.result {
+            self.deadlines
+                .insert(msg.id, now_millis() + self.sla.as_millis() as u64);
+        }
+        self.record(if msg.result {
+            JournalEntry::Approved {
+                id: msg.id,
+                deadline: self.deadlines.get(&msg.id).copied(),
+            }
+        } else {
+            JournalEntry::Rejected { id: msg.id }
+        });
         if msg.result {
             self.logger.log(
                 LogLevel::OrderAproved,
@@ -116,7 +536,7 @@ impl Interface {
             if let Some(order) = self.orders.get(&msg.id) {
                 let order_cloned = order.clone();
                 let order_dto: DTO = self.create_order(&order_cloned);
-                let result = self.send_order_to_robot(order_dto.serialize().as_str());
+                let result = self.send_order_to_robot(msg.id, order_dto.serialize().as_str());
                 match result {
                     Ok(_) => self
                         .logger
@@ -132,99 +552,428 @@ impl Interface {
                 LogLevel::OrderRejected,
                 format!("Order {} Rejected", msg.id).as_str(),
             );
+            if let Some(result) = self.results.get_mut(&msg.id) {
+                result.outcome = OrderOutcome::Rejected;
+                result.payment = StatusPayment::Unpaid;
+            }
         }
     }
 
     fn handle_robot(&mut self, msg: RobotMessage) {
-        let result = if msg.result {
-            "Completed"
-        } else {
-            "Incompleted"
-        };
-        self.logger.log(
-            LogLevel::StatusOrder,
-            format!("Order {} {}", msg.id, result).as_str(),
+        self.finish_order(msg.id, msg.result);
+        Logger.log(
+            LogLevel::Info,
+            format!("Enviando ACK a {}", msg.addr).as_str(),
         );
-        let msg_gateway = format!("{},{}", msg.id, msg.result);
-        self.stream.write_all(b"Payment:").unwrap(); // Especifico que es una orden lo que envio
-        self.stream.write_all(msg_gateway.as_bytes()).unwrap();
-        self.stream.write_all(b"\n").unwrap(); // Append newline delimiter
+        let _ = udp::send_message(
+            &self.socket,
+            format!("Ack:Result_Interface:{}", msg.id).as_str(),
+            msg.addr,
+        );
+    }
 
+    fn handle_resilience(&mut self, msg: ResilienceMessage) {
+        match msg.owner {
+            Some(owner) if owner != self.addr => self.adopt_order(owner, msg.id, msg.result),
+            _ => self.finish_order(msg.id, msg.result),
+        }
         Logger.log(
             LogLevel::Info,
             format!("Enviando ACK a {}", msg.addr).as_str(),
         );
-        let _ = self
-            .socket
-            .send_to("Ack:Result_Interface".as_bytes(), msg.addr);
+        let _ = udp::send_message(
+            &self.socket,
+            format!("Ack:Resilience:{}", msg.id).as_str(),
+            msg.addr,
+        );
     }
 
-    fn handle_resilience(&mut self, msg: ResilienceMessage) {
-        let result = if msg.result {
+    /// Registra el resultado del robot y le pide al gateway que cobre o anule
+    /// el pago. Un resultado repetido (reintento del robot o de la
+    /// resiliencia) solo se vuelve a confirmar, para no cobrar dos veces. El
+    /// pago queda pendiente hasta que se le manda al gateway.
+    fn finish_order(&mut self, id_order: usize, completed: bool) {
+        match self.results.get(&id_order).map(|result| result.outcome) {
+            Some(OrderOutcome::Expired) => {
+                self.logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "Order {} result arrived after its deadline, ignoring",
+                        id_order
+                    )
+                    .as_str(),
+                );
+                return;
+            }
+            Some(OrderOutcome::Pending) | None => {}
+            Some(_) => return,
+        }
+        self.deadlines.remove(&id_order);
+        let result = if completed {
             "Completed"
         } else {
             "Incompleted"
         };
         self.logger.log(
             LogLevel::StatusOrder,
-            format!("Order {} {}", msg.id, result).as_str(),
+            format!("Order {} {}", id_order, result).as_str(),
         );
-        let msg_gateway = format!("{},{}", msg.id, msg.result);
-        self.stream.write_all(b"Payment:").unwrap(); // Especifico que es una orden lo que envio
-        self.stream.write_all(msg_gateway.as_bytes()).unwrap();
-        self.stream.write_all(b"\n").unwrap(); // Append newline delimiter
+        if let Some(entry) = self.results.get_mut(&id_order) {
+            entry.outcome = OrderOutcome::from_result(completed);
+        }
+        self.record(JournalEntry::Finished {
+            id: id_order,
+            completed,
+        });
+        self.send_to_gateway(GatewayLine::Payment {
+            id: id_order,
+            completed,
+            owner: None,
+        });
+    }
+
+    /// Da por vencidos los pedidos aprobados cuyo plazo paso sin resultado del
+    /// robot: deja de mandarselos al lider y le pide al gateway que anule el
+    /// pago. Si despues llega el resultado, se ignora.
+    fn check_deadlines(&mut self) {
+        let now = now_millis();
+        let expired: Vec<usize> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id_order in expired {
+            self.deadlines.remove(&id_order);
+            self.logger.log(
+                LogLevel::OrderRejected,
+                format!(
+                    "Order {} expired without a result, voiding payment",
+                    id_order
+                )
+                .as_str(),
+            );
+            if self.unacked.remove(&id_order).is_some() {
+                if let Some(leader) = self.leader {
+                    self.ack_manager
+                        .remove(format!("Order:{}", id_order), leader);
+                }
+            }
+            if let Some(result) = self.results.get_mut(&id_order) {
+                result.outcome = OrderOutcome::Expired;
+            }
+            self.record(JournalEntry::Expired { id: id_order });
+            self.send_to_gateway(GatewayLine::Payment {
+                id: id_order,
+                completed: false,
+                owner: None,
+            });
+        }
+    }
+
+    /// Toma el resultado de un pedido de otra interfaz que no respondio y le
+    /// pide al gateway que cobre o anule el pago en su lugar.
+    fn adopt_order(&mut self, owner: SocketAddr, id_order: usize, completed: bool) {
+        if self.adopted.contains_key(&(owner, id_order)) {
+            return;
+        }
+        self.logger.log(
+            LogLevel::StatusOrder,
+            format!(
+                "Order {} of interface {} adopted: {}",
+                id_order,
+                owner,
+                if completed {
+                    "Completed"
+                } else {
+                    "Incompleted"
+                }
+            )
+            .as_str(),
+        );
+        let mut result = OrderResult::new(id_order);
+        result.outcome = OrderOutcome::from_result(completed);
+        self.adopted.insert((owner, id_order), result);
+        self.record(JournalEntry::Adopted {
+            owner,
+            id: id_order,
+            completed,
+        });
+        self.send_to_gateway(GatewayLine::Payment {
+            id: id_order,
+            completed,
+            owner: Some(owner),
+        });
+    }
 
+    fn process_orders(&mut self, file: OrderFile) {
         Logger.log(
             LogLevel::Info,
-            format!("Enviando ACK a {}", msg.addr).as_str(),
+            format!(
+                "Interface {} has {} orders, {} invalid",
+                self.id,
+                file.orders.len(),
+                file.invalid.len()
+            )
+            .as_str(),
         );
-        let _ = self.socket.send_to("Ack:Resilience".as_bytes(), msg.addr);
+        for record in file.invalid.iter() {
+            self.logger.log(
+                LogLevel::Error,
+                format!(
+                    "Skipping record at index {} (id {:?}): {}",
+                    record.index, record.id, record.reason
+                )
+                .as_str(),
+            );
+        }
+        self.invalid.extend(file.invalid);
+        for order in file.orders {
+            // Los que ya estan en el diario se retomaron al arrancar
+            if self.results.contains_key(&order.id) {
+                self.logger.log(
+                    LogLevel::Info,
+                    format!("Order {} already processed, skipping", order.id).as_str(),
+                );
+                continue;
+            }
+            self.submit_order(order);
+        }
     }
 
-    fn process_orders(&mut self) {
-        let list = read_file(&self.file_path);
-        self.orders = list.clone();
-        Logger.log(
+    fn record(&mut self, entry: JournalEntry) {
+        if let Err(e) = self.journal.record(&entry) {
+            self.logger.log(
+                LogLevel::Error,
+                format!("[Interface {}] Error writing journal: {}", self.id, e).as_str(),
+            );
+        }
+    }
+
+    /// Retoma los pedidos del diario segun donde quedaron: vuelve a pedir la
+    /// autorizacion de los que no tuvieron respuesta del gateway, le reenvia
+    /// al lider los aprobados que no confirmo y cobra o anula los que ya
+    /// tienen resultado. Los aceptados por el lider esperan su resultado hasta
+    /// el plazo anotado; sin plazo anotado se cuenta desde que se retoman.
+    fn resume_orders(&mut self) {
+        let recovered = std::mem::take(&mut self.recovered);
+        if recovered.orders.is_empty() && recovered.adopted.is_empty() {
+            return;
+        }
+        self.logger.log(
             LogLevel::Info,
-            format!("Interface {} has {} orders", self.id, list.len()).as_str(),
+            format!(
+                "[Interface {}] Resuming {} orders and {} adopted orders from the journal",
+                self.id,
+                recovered.orders.len(),
+                recovered.adopted.len()
+            )
+            .as_str(),
         );
-        for order in list {
-            let dto = self.create_order(&order.1);
-            let result = self.send_order_to_gateway(dto.serialize().as_str());
-            match result {
-                Ok(_) => self.logger.log(LogLevel::Info, "Message sent to gateway"),
+        for adoption in recovered.adopted {
+            let RecoveredAdoption {
+                owner,
+                id,
+                completed,
+                settled,
+            } = adoption;
+            let mut result = OrderResult::new(id);
+            result.outcome = OrderOutcome::from_result(completed);
+            if settled {
+                result.payment = StatusPayment::settled(completed);
+            } else {
+                self.send_to_gateway(GatewayLine::Payment {
+                    id,
+                    completed,
+                    owner: Some(owner),
+                });
+            }
+            self.adopted.insert((owner, id), result);
+        }
+        for RecoveredOrder {
+            order,
+            stage,
+            deadline,
+        } in recovered.orders
+        {
+            let id = order.id;
+            let mut result = OrderResult::new(id);
+            if matches!(stage, Stage::Approved | Stage::Accepted) {
+                let deadline =
+                    deadline.unwrap_or_else(|| now_millis() + self.sla.as_millis() as u64);
+                self.deadlines.insert(id, deadline);
+            }
+            match stage {
+                Stage::Authorizing => {
+                    let dto = self.create_order(&order).serialize();
+                    self.authorizing.insert(id);
+                    self.send_to_gateway(GatewayLine::Order { id, dto });
+                }
+                Stage::Approved => {
+                    let dto = self.create_order(&order).serialize();
+                    if let Err(e) = self.send_order_to_robot(id, &dto) {
+                        self.logger.log(
+                            LogLevel::Error,
+                            format!("Error sending message to Leader Robots: {}", e).as_str(),
+                        );
+                    }
+                }
+                Stage::Accepted => {}
+                Stage::Rejected => {
+                    result.outcome = OrderOutcome::Rejected;
+                    result.payment = StatusPayment::Unpaid;
+                }
+                Stage::Finished { completed } => {
+                    result.outcome = OrderOutcome::from_result(completed);
+                    self.send_to_gateway(GatewayLine::Payment {
+                        id,
+                        completed,
+                        owner: None,
+                    });
+                }
+                Stage::Settled { completed } => {
+                    result.outcome = OrderOutcome::from_result(completed);
+                    result.payment = StatusPayment::settled(completed);
+                }
+                Stage::Expired { settled } => {
+                    result.outcome = OrderOutcome::Expired;
+                    if settled {
+                        result.payment = StatusPayment::Unpaid;
+                    } else {
+                        self.send_to_gateway(GatewayLine::Payment {
+                            id,
+                            completed: false,
+                            owner: None,
+                        });
+                    }
+                }
+            }
+            self.results.insert(id, result);
+            self.orders.insert(id, order);
+        }
+    }
+
+    /// Toma los archivos nuevos de la carpeta vigilada y manda sus pedidos,
+    /// salvo los que ya se mandaron antes. Despues de cada archivo se guarda
+    /// el estado, para que un reinicio no los repita.
+    fn scan_folder(&mut self) {
+        let Some(mut watch) = self.watch.take() else {
+            return;
+        };
+        let ready = match watch.ready_files() {
+            Ok(ready) => ready,
+            Err(e) => {
+                self.logger.log(
+                    LogLevel::Error,
+                    format!("[Interface {}] Error reading folder: {}", self.id, e).as_str(),
+                );
+                Vec::new()
+            }
+        };
+        for (name, path) in ready {
+            self.logger.log(
+                LogLevel::Info,
+                format!("[Interface {}] New orders file {}", self.id, name).as_str(),
+            );
+            match read_file(&path.to_string_lossy(), self.format) {
+                Ok(mut file) => {
+                    let orders = std::mem::take(&mut file.orders);
+                    let mut taken = watch.state.take_orders(&name, orders);
+                    for (id, previous) in taken.collisions {
+                        self.logger.log(
+                            LogLevel::Error,
+                            format!(
+                                "[Interface {}] Order {} in {} reuses an id from {}, skipping",
+                                self.id, id, name, previous
+                            )
+                            .as_str(),
+                        );
+                    }
+                    // Los que recupero el diario tambien se mandaron
+                    let (known, new): (Vec<Order>, Vec<Order>) = taken
+                        .new
+                        .into_iter()
+                        .partition(|order| self.results.contains_key(&order.id));
+                    taken.repeated.extend(known.iter().map(|order| order.id));
+                    for id in taken.repeated {
+                        self.logger.log(
+                            LogLevel::Info,
+                            format!("Order {} already processed, skipping", id).as_str(),
+                        );
+                    }
+                    file.orders = new;
+                    self.process_orders(file);
+                }
                 Err(e) => self.logger.log(
                     LogLevel::Error,
-                    format!("Error sending message to gateway: {}", e).as_str(),
+                    format!("[Interface {}] Skipping file {}: {}", self.id, name, e).as_str(),
                 ),
             }
+            watch.state.files.insert(name);
+            if let Err(e) = watch.state.save() {
+                self.logger.log(
+                    LogLevel::Error,
+                    format!("[Interface {}] Error saving folder state: {}", self.id, e).as_str(),
+                );
+            }
         }
+        self.watch = Some(watch);
     }
 
-    fn create_order(&mut self, order: &Order) -> DTO {
-        let mut result = DTO {
+    /// Manda el pedido al gateway para cobrarlo. Si se aprueba, sigue al lider.
+    fn submit_order(&mut self, mut order: Order) {
+        let mut result = OrderResult::new(order.id);
+        let hour = self.pricing.current_hour();
+        match self.pricing.total(order.amount, &order.products, hour) {
+            Ok(total) => order.total_price = total,
+            Err(e) => {
+                self.logger.log(
+                    LogLevel::OrderRejected,
+                    format!("Order {} Rejected: {}", order.id, e).as_str(),
+                );
+                result.outcome = OrderOutcome::Rejected;
+                result.payment = StatusPayment::Unpaid;
+                self.results.insert(order.id, result);
+                return;
+            }
+        }
+        let dto = self.create_order(&order).serialize();
+        self.record(JournalEntry::Submitted {
+            order: order.clone(),
+        });
+        self.results.insert(order.id, result);
+        self.authorizing.insert(order.id);
+        self.send_to_gateway(GatewayLine::Order { id: order.id, dto });
+        self.orders.insert(order.id, order);
+    }
+
+    /// El total ya viene calculado por `Pricing` al cargar el pedido.
+    fn create_order(&self, order: &Order) -> DTO {
+        DTO {
             id_order: order.id,
             id_interface: self.id,
             ice_creams: order.products.clone(),
             size_order: order.amount,
             cash_card: order.card_cash,
             total_amount: order.total_price,
-        };
-
-        if result.size_order == 0.25 {
-            result.total_amount = 500;
-        } else if result.size_order == 0.5 {
-            result.total_amount = 850;
-        } else if result.size_order == 1.0 {
-            result.total_amount = 1500;
+            owner: self.addr,
+            takeover: self.peers.clone(),
+            deadline: self.deadlines.get(&order.id).copied(),
         }
-        result
     }
 
     fn handle_ack(&mut self, msg: AckMessage) {
-        match msg.msg.as_str() {
-            "Order" => self.ack_manager.remove(msg.msg, msg.addr),
-            _ => {}
+        if let Some(id_order) = msg.msg.strip_prefix("Order:") {
+            if let Ok(id_order) = id_order.parse::<usize>() {
+                if self.unacked.remove(&id_order).is_some() {
+                    self.logger.log(
+                        LogLevel::StatusOrder,
+                        format!("Order {} accepted by the leader", id_order).as_str(),
+                    );
+                    self.record(JournalEntry::Accepted { id: id_order });
+                }
+            }
+            self.ack_manager.remove(msg.msg, msg.addr);
         }
     }
 }
@@ -233,34 +982,33 @@ impl Actor for Interface {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Self::Context) {
+        self.discover_leader();
+        // Tambien se pregunta si hay pedidos sin confirmar, por si el lider
+        // que se conoce ya no atiende
+        _ctx.run_interval(DISCOVERY_INTERVAL, |act, _ctx| {
+            if act.leader.is_none() || !act.unacked.is_empty() {
+                act.discover_leader();
+            }
+        });
         self.logger.log(
             LogLevel::Info,
             format!("[Interface {}] Started", self.id).as_str(),
         );
-        // self.stream = Some(connection);
-        self.logger.log(LogLevel::Info, "Connected to gateway");
+        self.connect_gateway(_ctx);
+        _ctx.run_interval(RECONNECT_MIN, |act, ctx| act.connect_gateway(ctx));
 
         let actor_addr = _ctx.address();
         let id_interface = self.id;
-        let socket = match self.socket.try_clone() {
-            Ok(socket) => socket,
-            Err(e) => {
-                Logger.log(
-                    LogLevel::Error,
-                    format!("Error cloning socket: {}", e).as_str(),
-                );
-                return;
-            }
-        };
+        let socket = Arc::clone(&self.socket);
 
         actix::spawn(async move {
             Logger.log(LogLevel::Info, "Esperando mensajes");
             let mut buffer = [0; 1024];
             loop {
-                match socket.recv_from(&mut buffer) {
+                match socket.recv_from(&mut buffer).await {
                     Ok((size, addr)) => {
                         let content = String::from_utf8_lossy(&buffer[..size]).to_string();
-                        actor_addr.send(Msg { content, addr }).await.unwrap();
+                        actor_addr.do_send(Msg { content, addr });
                     }
                     Err(e) => {
                         Logger.log(
@@ -277,7 +1025,14 @@ impl Actor for Interface {
             }
         });
 
-        self.process_orders();
+        self.resume_orders();
+        if let Some(file) = self.file.take() {
+            self.process_orders(file);
+        }
+        _ctx.run_interval(DEADLINE_INTERVAL, |act, _ctx| act.check_deadlines());
+        if self.watch.is_some() {
+            _ctx.run_interval(WATCH_INTERVAL, |act, _ctx| act.scan_folder());
+        }
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
@@ -314,16 +1069,36 @@ impl Handler<Msg> for Interface {
                 })
             }
         } else if msg.content.starts_with("Resilience:") {
+            // Resilience:id,resultado[,interfaz duena]
             let content: Vec<&str> = msg.content[11..].split(',').collect();
-            if content.len() == 2 {
+            if content.len() == 2 || content.len() == 3 {
                 let id_order: usize = content[0].parse::<usize>().unwrap();
                 let result: bool = content[1] == "true";
                 _ctx.address().do_send(ResilienceMessage {
                     id: id_order,
                     result,
+                    owner: content.get(2).and_then(|owner| owner.parse().ok()),
                     addr: msg.addr,
                 })
             }
+        } else if msg.content.starts_with("Leader:") {
+            let content: Vec<&str> = msg.content[7..].split(',').collect();
+            if content.len() == 3 {
+                if let (Ok(epoch), Ok(endpoint)) = (content[1].parse(), content[2].parse()) {
+                    _ctx.address().do_send(LeaderMessage { epoch, endpoint });
+                }
+            }
+        } else if msg.content.starts_with("NewLeader:") {
+            let content: Vec<&str> = msg.content[10..].split(',').collect();
+            if content.len() == 3 {
+                if let (Ok(epoch), Ok(endpoint)) = (content[1].parse(), content[2].parse()) {
+                    _ctx.address().do_send(NewLeaderMessage {
+                        epoch,
+                        endpoint,
+                        addr: msg.addr,
+                    });
+                }
+            }
         } else if msg.content.starts_with("Ack:") {
             let content: String = msg.content[4..].parse().unwrap();
             _ctx.address().do_send(AckMessage {
@@ -358,6 +1133,83 @@ impl Handler<ResilienceMessage> for Interface {
     }
 }
 
+impl Handler<LeaderMessage> for Interface {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaderMessage, _ctx: &mut Self::Context) {
+        self.handle_leader(msg);
+    }
+}
+
+impl Handler<NewLeaderMessage> for Interface {
+    type Result = ();
+
+    fn handle(&mut self, msg: NewLeaderMessage, _ctx: &mut Self::Context) {
+        let _ = udp::send_message(&self.socket, "Ack:NewLeader", msg.addr);
+        self.handle_leader(LeaderMessage {
+            epoch: msg.epoch,
+            endpoint: msg.endpoint,
+        });
+    }
+}
+
+impl Handler<SubmitOrder> for Interface {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubmitOrder, _ctx: &mut Self::Context) {
+        self.logger.log(
+            LogLevel::StatusOrder,
+            format!("Order {} submitted", msg.order.id).as_str(),
+        );
+        self.submit_order(msg.order);
+    }
+}
+
+impl Handler<GetReport> for Interface {
+    type Result = MessageResult<GetReport>;
+
+    fn handle(&mut self, _msg: GetReport, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(InterfaceReport::new(
+            self.id,
+            self.results.values().cloned().collect(),
+            self.invalid.clone(),
+            self.adopted
+                .iter()
+                .map(|((owner, _), result)| AdoptedOrder {
+                    owner: *owner,
+                    result: result.clone(),
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Handler<GatewayConnected> for Interface {
+    type Result = ();
+
+    fn handle(&mut self, msg: GatewayConnected, ctx: &mut Self::Context) {
+        self.gateway_connected(msg.stream, ctx);
+    }
+}
+
+impl Handler<GatewayWritten> for Interface {
+    type Result = ();
+
+    fn handle(&mut self, msg: GatewayWritten, _ctx: &mut Self::Context) {
+        self.gateway_written(msg.connection);
+    }
+}
+
+impl Handler<GatewayDisconnected> for Interface {
+    type Result = ();
+
+    fn handle(&mut self, msg: GatewayDisconnected, _ctx: &mut Self::Context) {
+        if msg.connection == self.connection {
+            self.gateway_lost();
+        }
+    }
+}
+
 impl Handler<AckMessage> for Interface {
     type Result = ();
 
@@ -365,3 +1217,155 @@ impl Handler<AckMessage> for Interface {
         self.handle_ack(msg);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Interfaz en un puerto libre, sin conexion al gateway, con un diario
+    /// temporal.
+    fn interface(name: &str) -> (Interface, std::path::PathBuf) {
+        let journal =
+            std::env::temp_dir().join(format!("journal_{}_{}.jsonl", std::process::id(), name));
+        let _ = std::fs::remove_file(&journal);
+        let mut config = InterfaceConfig::new(1, OrderSource::Interactive).unwrap();
+        config.addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        config.journal = journal.to_string_lossy().to_string();
+        (Interface::new(config).unwrap(), journal)
+    }
+
+    #[test]
+    fn payment_lines_carry_the_owner_of_adopted_orders() {
+        let own = GatewayLine::Payment {
+            id: 4,
+            completed: true,
+            owner: None,
+        };
+        assert_eq!(own.text(), "Payment:4,true\n");
+        let adopted = GatewayLine::Payment {
+            id: 4,
+            completed: false,
+            owner: Some("127.0.0.1:9002".parse().unwrap()),
+        };
+        assert_eq!(adopted.text(), "Payment:4,false,127.0.0.1:9002\n");
+    }
+
+    fn order_line(id: usize) -> GatewayLine {
+        GatewayLine::Order {
+            id,
+            dto: format!("{{\"id_order\":{}}}", id),
+        }
+    }
+
+    fn texts(lines: &VecDeque<GatewayLine>) -> Vec<String> {
+        lines.iter().map(GatewayLine::text).collect()
+    }
+
+    #[test]
+    fn a_dropped_connection_neither_loses_nor_repeats_queued_lines() {
+        let payment = GatewayLine::Payment {
+            id: 5,
+            completed: true,
+            owner: None,
+        };
+        let mut outbox: VecDeque<GatewayLine> =
+            VecDeque::from(vec![order_line(1), order_line(2), payment, order_line(3)]);
+        let authorizing = BTreeSet::from([1, 2, 3]);
+        let expected = texts(&outbox);
+
+        // Se pasan tres lineas al hilo que escribe, sale solo la primera y
+        // se corta la conexion
+        let mut sending: VecDeque<GatewayLine> = outbox.drain(..3).collect();
+        sending.pop_front();
+        let resend = requeue_for_reconnect(&mut outbox, &mut sending, &authorizing);
+        assert!(sending.is_empty());
+        assert_eq!(texts(&outbox), expected[1..]);
+        // El pedido 1 salio sin respuesta: se vuelve a pedir una sola vez
+        assert_eq!(resend, vec![1]);
+        outbox.push_front(order_line(1));
+        assert_eq!(texts(&outbox), expected);
+
+        // Si se vuelve a cortar antes de escribir nada no se repite nada
+        let resend = requeue_for_reconnect(&mut outbox, &mut sending, &authorizing);
+        assert!(resend.is_empty());
+        assert_eq!(texts(&outbox), expected);
+    }
+
+    #[actix_rt::test]
+    async fn a_missed_deadline_voids_the_payment_and_ignores_a_late_result() {
+        let (mut interface, journal) = interface("deadline");
+        interface.results.insert(7, OrderResult::new(7));
+        interface.deadlines.insert(7, now_millis() - 1);
+        interface.results.insert(8, OrderResult::new(8));
+        interface.deadlines.insert(8, now_millis() + 60_000);
+
+        interface.check_deadlines();
+        assert_eq!(texts(&interface.outbox), vec!["Payment:7,false\n"]);
+        assert_eq!(interface.results[&7].outcome, OrderOutcome::Expired);
+        assert_eq!(interface.results[&8].outcome, OrderOutcome::Pending);
+        // Ya se anulo: no se vuelve a anular en la proxima revision
+        interface.check_deadlines();
+        assert_eq!(interface.outbox.len(), 1);
+
+        // El resultado tardio del robot no cobra
+        interface.finish_order(7, true);
+        assert_eq!(texts(&interface.outbox), vec!["Payment:7,false\n"]);
+        assert_eq!(interface.results[&7].outcome, OrderOutcome::Expired);
+
+        // Uno a tiempo si
+        interface.finish_order(8, true);
+        assert_eq!(
+            texts(&interface.outbox),
+            vec!["Payment:7,false\n", "Payment:8,true\n"]
+        );
+        interface.ack_manager.stop();
+        std::fs::remove_file(journal).unwrap();
+    }
+
+    /// Lo que llega al socket hasta que pasa `wait` sin mensajes.
+    async fn received(socket: &UdpSocket, wait: Duration) -> Vec<String> {
+        let mut received = Vec::new();
+        let mut buffer = [0; 1024];
+        while let Ok(Ok((size, _))) =
+            tokio::time::timeout(wait, socket.recv_from(&mut buffer)).await
+        {
+            received.push(String::from_utf8_lossy(&buffer[..size]).to_string());
+        }
+        received.sort();
+        received
+    }
+
+    #[actix_rt::test]
+    async fn a_new_leader_gets_exactly_the_unacked_orders() {
+        let (mut interface, journal) = interface("leader");
+        let old = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        interface.leader = Some(old.local_addr().unwrap());
+        interface.leader_epoch = 1;
+        // El pedido 2 ya lo confirmo el lider anterior
+        interface.unacked.insert(1, "Order:uno".to_string());
+        interface.unacked.insert(3, "Order:tres".to_string());
+
+        interface.handle_leader(LeaderMessage {
+            epoch: 2,
+            endpoint: new.local_addr().unwrap(),
+        });
+        let wait = Duration::from_millis(300);
+        assert_eq!(received(&new, wait).await, vec!["Order:tres", "Order:uno"]);
+
+        // El mismo anuncio repetido o uno de una epoca vieja no reenvia nada
+        interface.handle_leader(LeaderMessage {
+            epoch: 2,
+            endpoint: new.local_addr().unwrap(),
+        });
+        interface.handle_leader(LeaderMessage {
+            epoch: 1,
+            endpoint: old.local_addr().unwrap(),
+        });
+        assert!(received(&new, wait).await.is_empty());
+        assert!(received(&old, wait).await.is_empty());
+        assert_eq!(interface.leader, Some(new.local_addr().unwrap()));
+        interface.ack_manager.stop();
+        std::fs::remove_file(journal).unwrap();
+    }
+}
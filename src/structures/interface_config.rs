@@ -0,0 +1,92 @@
+use crate::common::read_file::OrderFormat;
+use crate::common::udp::local_addr;
+use crate::structures::pricing::PricingConfig;
+use crate::structures::robot_config::default_robot_addr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_ROBOT_IDS: std::ops::Range<usize> = 1..10;
+// Tiempo maximo para que terminen todos los pedidos
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+// Plazo de entrega de cada pedido desde que se aprueba el pago
+const DEFAULT_SLA: Duration = Duration::from_secs(30);
+
+/// Puerto por defecto de una interfaz segun su id. Falla si el id no entra
+/// en un puerto.
+pub fn default_interface_addr(id: usize) -> Result<SocketAddr, String> {
+    local_addr(9000, id)
+}
+
+/// De donde saca los pedidos una interfaz.
+#[derive(Debug, Clone)]
+pub enum OrderSource {
+    /// Un archivo que se procesa entero al arrancar.
+    File(String),
+    /// Los carga a mano un cajero.
+    Interactive,
+    /// Cada archivo nuevo que aparece en la carpeta.
+    Watch(PathBuf),
+}
+
+/// Configuracion con la que arranca una interfaz.
+#[derive(Debug, Clone)]
+pub struct InterfaceConfig {
+    pub id: usize,
+    /// Donde escucha la interfaz; es la direccion duena de sus pedidos.
+    pub addr: SocketAddr,
+    pub source: OrderSource,
+    /// Formato de los archivos. Sin formato se deduce de la extension.
+    pub format: Option<OrderFormat>,
+    pub robots: Vec<SocketAddr>,
+    /// Interfaces que toman los pedidos de esta si no responde, en el orden en
+    /// que se prueban.
+    pub peers: Vec<SocketAddr>,
+    /// Archivo JSON donde se escribe el resultado de cada pedido.
+    pub results: String,
+    /// Cuanto se espera a que terminen los pedidos antes de salir igual.
+    pub timeout: Duration,
+    /// Plazo para entregar un pedido desde que el gateway aprueba el pago.
+    /// Pasado el plazo se anula el pago.
+    pub sla: Duration,
+    /// Diario de pedidos para retomarlos si la interfaz se cae.
+    pub journal: String,
+    pub pricing: PricingConfig,
+}
+
+impl InterfaceConfig {
+    /// Configuracion por defecto: pregunta por el lider a los puertos por
+    /// defecto de los robots 1 a 9, no le deja sus pedidos a ninguna otra
+    /// interfaz y escribe los resultados en
+    /// `results_<id>.json`, con el diario en `journal_<id>.jsonl`. Cobra con la
+    /// lista de precios por defecto y da 30 segundos para entregar cada pedido.
+    /// Escucha en 9000+id y falla si el id no entra en ese puerto.
+    pub fn new(id: usize, source: OrderSource) -> Result<InterfaceConfig, String> {
+        Ok(InterfaceConfig {
+            id,
+            addr: default_interface_addr(id)?,
+            source,
+            format: None,
+            robots: DEFAULT_ROBOT_IDS
+                .filter_map(|robot| default_robot_addr(robot).ok())
+                .collect(),
+            peers: Vec::new(),
+            results: format!("results_{}.json", id),
+            timeout: DEFAULT_TIMEOUT,
+            sla: DEFAULT_SLA,
+            journal: format!("journal_{}.jsonl", id),
+            pricing: PricingConfig::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn by_default_orders_are_not_left_to_other_interfaces() {
+        let config = InterfaceConfig::new(3, OrderSource::Interactive).unwrap();
+        assert!(config.peers.is_empty());
+    }
+}
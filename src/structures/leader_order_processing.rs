@@ -1,230 +1,742 @@
 use crate::common::log::{LogLevel, Logger};
-use crate::common::protocol::DTO;
+use crate::common::protocol::{OrderKey, DTO};
+use crate::common::udp;
 use crate::defines::ack::Ack;
+use crate::defines::flavours::FLAVOURS;
 use crate::structures::ack_manager::AckManager;
 use crate::structures::ice_cream::IceCreamContainer;
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::to_string_pretty;
-use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
-use std::thread;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+// Tiempos usados para calcular el plazo de cada pedido asignado a un robot
+const BASE_PROCESSING_TIME: Duration = Duration::from_secs(5);
+const TIME_PER_FLAVOUR: Duration = Duration::from_secs(2);
+const TIME_PER_KG: Duration = Duration::from_secs(4);
+const MAX_EXTENSION: Duration = Duration::from_secs(30);
+
+// Cada cuanto el lider revisa si algun robot se paso de su plazo
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Plazo que tiene un robot para completar un pedido segun su tamaño y la
+/// cantidad de sabores (cada sabor implica un ida y vuelta de stock).
+pub fn processing_deadline(dto: &DTO) -> Duration {
+    let flavours = dto.ice_creams.len().max(1) as u32;
+    BASE_PROCESSING_TIME
+        + TIME_PER_FLAVOUR * flavours
+        + TIME_PER_KG.mul_f32(dto.size_order.max(0.0))
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Msg {
+    content: String,
+    addr: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RegisterRobot {
+    pub addr: SocketAddr,
+    pub capacity: usize,
+    pub incarnation: u64,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OrderFinished {
+    pub addr: SocketAddr,
+    pub key: OrderKey,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ExtendDeadline {
+    pub addr: SocketAddr,
+    pub key: OrderKey,
+    pub extension: Duration,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReserveStock {
+    pub key: OrderKey,
+    pub ice_creams: Vec<String>,
+    pub amount: f64,
+    pub epoch: u64,
+    pub addr: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DrainRobot {
+    pub addr: SocketAddr,
+    pub handed_back: Vec<OrderKey>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RemoveRobot {
+    pub addr: SocketAddr,
+}
+
+/// Cambio al estado del lider. Con Raft cada comando pasa primero por el log
+/// replicado; sin Raft se aplica en el momento.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    AddOrder {
+        dto: DTO,
+        interface: SocketAddr,
+    },
+    AddRobot {
+        addr: SocketAddr,
+        capacity: usize,
+        incarnation: u64,
+    },
+    FinishOrder {
+        addr: SocketAddr,
+        key: OrderKey,
+    },
+    ExpireOrder {
+        addr: SocketAddr,
+        key: OrderKey,
+    },
+    DrainRobot {
+        addr: SocketAddr,
+        handed_back: Vec<OrderKey>,
+    },
+    RemoveRobot {
+        addr: SocketAddr,
+    },
+    UseStock {
+        key: OrderKey,
+        ice_creams: Vec<String>,
+        amount: f64,
+        addr: SocketAddr,
+    },
+}
+
+/// Pedido del lider al robot para que agregue un comando al log de Raft.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Propose {
+    pub command: Command,
+}
+
+/// Comandos ya confirmados por la mayoria, en el orden del log.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Apply {
+    pub commands: Vec<Command>,
+}
+
+/// La replica pasa a ser el lider del mandato indicado.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Lead {
+    pub epoch: u64,
+}
+
+/// La replica deja de ser lider y solo aplica lo que le llega del log.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Follow;
+
+/// Entrega el estado del lider y frena el actor, liberando su puerto.
+#[derive(Message)]
+#[rtype(result = "LeaderSnapshot")]
+pub struct HandOver;
+
+/// Copia serializable del estado del lider, usada para traspasar el rol.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LeaderSnapshot {
+    pub epoch: u64,
+    pub orders: Vec<DTO>,
+    pub working: Vec<(SocketAddr, DTO)>,
+    pub robots: Vec<(SocketAddr, usize)>,
+    pub draining: Vec<SocketAddr>,
+    pub stock: Vec<(String, f64)>,
+    #[serde(default)]
+    pub reserved: Vec<(OrderKey, bool)>,
+    #[serde(default)]
+    pub incarnations: Vec<(SocketAddr, u64)>,
+}
+
+/// Resultado de dar de alta un robot.
+#[derive(Debug)]
+pub enum Registration {
+    /// Un robot que no estaba registrado.
+    New,
+    /// El mismo robot que ya estaba (por ejemplo, un Announce repetido).
+    Known,
+    /// El robot se reinicio con la misma direccion y perdio lo que estaba
+    /// haciendo: los pedidos que tenia se le vuelven a mandar hasta su
+    /// capacidad nueva y el resto vuelve a la cola.
+    Restarted {
+        resumed: Vec<DTO>,
+        requeued: Vec<DTO>,
+    },
+}
 
 #[derive(Debug, Clone)]
-struct TimedItem {
-    item_type: SocketAddr,
-    n_order: usize,
+struct Assignment {
+    dto: DTO,
     expiration: Instant,
 }
 
-impl TimedItem {
-    fn new(item: SocketAddr, id: usize, duration: Duration) -> Self {
-        TimedItem {
-            item_type: item,
-            n_order: id,
-            expiration: Instant::now() + duration,
-        }
-    }
+/// Estado del lider: cola de pedidos, pedidos en curso por robot, lugares
+/// libres de cada robot y stock. No hace I/O, asi se puede probar aislado.
+pub struct LeaderState {
+    orders: VecDeque<DTO>,
+    working: HashMap<SocketAddr, Vec<Assignment>>,
+    robots: HashMap<SocketAddr, usize>,
+    draining: HashSet<SocketAddr>,
+    free_slots: VecDeque<SocketAddr>,
+    stock: HashMap<String, IceCreamContainer>,
+    reserved: HashMap<OrderKey, bool>,
+    incarnations: HashMap<SocketAddr, u64>,
 }
 
-struct LeaderFlag {
-    is_leader: Mutex<bool>,
-    condvar: Condvar,
+impl Default for LeaderState {
+    fn default() -> Self {
+        LeaderState::new()
+    }
 }
 
-impl LeaderFlag {
-    fn new() -> LeaderFlag {
-        LeaderFlag {
-            is_leader: Mutex::new(false),
-            condvar: Condvar::new(),
+impl LeaderState {
+    pub fn new() -> LeaderState {
+        let stock: HashMap<String, IceCreamContainer> = FLAVOURS
+            .iter()
+            .map(|flavour| (flavour.to_string(), IceCreamContainer::new(10.0)))
+            .collect();
+
+        LeaderState {
+            orders: VecDeque::new(),
+            working: HashMap::new(),
+            robots: HashMap::new(),
+            draining: HashSet::new(),
+            free_slots: VecDeque::new(),
+            stock,
+            reserved: HashMap::new(),
+            incarnations: HashMap::new(),
         }
     }
 
-    fn wait_for_leader(&self) {
-        let leader = self.is_leader.lock().unwrap();
-        let _guard = self.condvar.wait_while(leader, |leader| !*leader).unwrap();
+    /// Reconstruye el estado a partir de la copia de otro lider. Los pedidos
+    /// en curso arrancan con un plazo nuevo.
+    pub fn from_snapshot(snapshot: LeaderSnapshot, now: Instant) -> LeaderState {
+        let mut state = LeaderState::new();
+        state.stock = snapshot
+            .stock
+            .into_iter()
+            .map(|(flavour, amount)| (flavour, IceCreamContainer::new(amount)))
+            .collect();
+        state.orders = snapshot.orders.into_iter().collect();
+        state.draining = snapshot.draining.into_iter().collect();
+        state.reserved = snapshot.reserved.into_iter().collect();
+        state.incarnations = snapshot.incarnations.into_iter().collect();
+        for (addr, dto) in snapshot.working {
+            let expiration = now + processing_deadline(&dto);
+            state
+                .working
+                .entry(addr)
+                .or_default()
+                .push(Assignment { dto, expiration });
+        }
+        for (addr, capacity) in snapshot.robots {
+            state.robots.insert(addr, capacity);
+            if state.draining.contains(&addr) {
+                continue;
+            }
+            let busy = state.working.get(&addr).map_or(0, |a| a.len());
+            for _ in busy..capacity {
+                state.free_slots.push_back(addr);
+            }
+        }
+        state
     }
 
-    fn set_leader(&self) {
-        let mut leader = self.is_leader.lock().unwrap();
-        *leader = true;
-        self.condvar.notify_all();
+    pub fn snapshot(&self) -> LeaderSnapshot {
+        LeaderSnapshot {
+            orders: self.orders.iter().cloned().collect(),
+            working: self
+                .working
+                .iter()
+                .flat_map(|(addr, assignments)| {
+                    assignments
+                        .iter()
+                        .map(move |assignment| (*addr, assignment.dto.clone()))
+                })
+                .collect(),
+            robots: self
+                .robots
+                .iter()
+                .map(|(addr, capacity)| (*addr, *capacity))
+                .collect(),
+            epoch: 0,
+            draining: self.draining.iter().copied().collect(),
+            stock: self
+                .stock
+                .iter()
+                .map(|(flavour, container)| (flavour.clone(), container.get_stock()))
+                .collect(),
+            reserved: self
+                .reserved
+                .iter()
+                .map(|(key, result)| (*key, *result))
+                .collect(),
+            incarnations: self
+                .incarnations
+                .iter()
+                .map(|(addr, incarnation)| (*addr, *incarnation))
+                .collect(),
+        }
     }
-}
 
-pub struct LeaderOrderProcessing {
-    orders_list: Arc<(Mutex<HashMap<usize, DTO>>, Condvar)>,
-    leader_flag: Arc<LeaderFlag>,
-    socket_leader: Arc<Mutex<Option<UdpSocket>>>,
-    condvar_socket: Arc<Condvar>,
-    working_list: Arc<Mutex<HashMap<SocketAddr, DTO>>>,
-    stock: Arc<RwLock<HashMap<String, IceCreamContainer>>>,
-    working_pending: Arc<(Mutex<Vec<TimedItem>>, Condvar)>,
-    pending_send_works: Arc<(Mutex<Vec<SocketAddr>>, Condvar)>,
-    ack_manager: Arc<Mutex<Option<AckManager>>>,
-}
-
-impl Default for LeaderOrderProcessing {
-    fn default() -> Self {
-        LeaderOrderProcessing::new()
+    fn is_known(&self, key: OrderKey) -> bool {
+        self.orders.iter().any(|dto| dto.key() == key)
+            || self
+                .working
+                .values()
+                .flatten()
+                .any(|assignment| assignment.dto.key() == key)
     }
-}
 
-impl Clone for LeaderOrderProcessing {
-    fn clone(&self) -> Self {
-        LeaderOrderProcessing {
-            orders_list: Arc::clone(&self.orders_list),
-            leader_flag: Arc::clone(&self.leader_flag),
-            socket_leader: Arc::clone(&self.socket_leader),
-            condvar_socket: Arc::clone(&self.condvar_socket),
-            working_list: Arc::clone(&self.working_list),
-            stock: Arc::clone(&self.stock),
-            working_pending: Arc::clone(&self.working_pending),
-            pending_send_works: Arc::clone(&self.pending_send_works),
-            ack_manager: Arc::clone(&self.ack_manager),
+    /// Encola un pedido. Devuelve false si ya estaba en cola o en curso
+    /// (por ejemplo, una orden reenviada por la interfaz). El mismo id de
+    /// pedido de otra interfaz es otro pedido.
+    pub fn add_order(&mut self, dto: DTO) -> bool {
+        if self.is_known(dto.key()) {
+            return false;
         }
+        self.orders.push_back(dto);
+        true
     }
-}
 
-impl LeaderOrderProcessing {
-    pub fn new() -> LeaderOrderProcessing {
-        let stock: HashMap<String, IceCreamContainer> = [
-            ("Chocolate".to_string(), IceCreamContainer::new(10.0)),
-            ("Vainilla".to_string(), IceCreamContainer::new(10.0)),
-            ("Crema Americana".to_string(), IceCreamContainer::new(10.0)),
-            ("Dulce de Leche".to_string(), IceCreamContainer::new(10.0)),
-            ("Frutilla".to_string(), IceCreamContainer::new(10.0)),
-        ]
-        .iter()
-        .cloned()
-        .collect();
-
-        let ret = LeaderOrderProcessing {
-            orders_list: Arc::new((Mutex::new(HashMap::new()), Condvar::new())),
-            leader_flag: Arc::new(LeaderFlag::new()),
-            socket_leader: Arc::new(Mutex::new(None)),
-            condvar_socket: Arc::new(Condvar::new()),
-            working_list: Arc::new(Mutex::new(HashMap::new())),
-            stock: Arc::new(RwLock::new(stock)),
-            working_pending: Arc::new((Mutex::new(Vec::new()), Condvar::new())),
-            pending_send_works: Arc::new((Mutex::new(Vec::new()), Condvar::new())),
-            ack_manager: Arc::new(Mutex::new(None)),
-        };
-        let mut clone = ret.clone();
-        thread::spawn(move || clone.receiver());
-        let mut clone_checking = ret.clone();
-        thread::spawn(move || clone_checking.checking_work());
-        let mut clone_send_work = ret.clone();
-        thread::spawn(move || clone_send_work.sending_work());
-        ret
-    }
-
-    fn sending_work(&mut self) {
-        loop {
-            self.wait_for_sender_work_pendings();
-            self.wait_for_orders();
-            let mut list = self.pending_send_works.0.lock().unwrap();
-
-            for i in 0..list.len() {
-                let addr = list[i];
-                self.send_work(addr);
-                list.remove(i);
-                // drop(list);
-                break;
+    /// Da de alta un robot con la cantidad de pedidos que puede tener en cola.
+    /// `incarnation` cambia cada vez que el robot arranca, asi se distingue un
+    /// reinicio de un alta repetida.
+    pub fn add_robot(
+        &mut self,
+        addr: SocketAddr,
+        capacity: usize,
+        incarnation: u64,
+        now: Instant,
+    ) -> Registration {
+        let capacity = capacity.max(1);
+        let previous = self.incarnations.insert(addr, incarnation);
+        if self.robots.insert(addr, capacity).is_none() {
+            for _ in 0..capacity {
+                self.free_slots.push_back(addr);
             }
+            return Registration::New;
+        }
+        if previous.is_none_or(|previous| previous == incarnation) {
+            return Registration::Known;
         }
+        // Se reinicio: lo anterior a su caida no vale, salvo lo que tenia asignado
+        self.draining.remove(&addr);
+        self.free_slots.retain(|slot| *slot != addr);
+        let mut assignments = self.working.remove(&addr).unwrap_or_default();
+        let kept = assignments.len().min(capacity);
+        let requeued: Vec<DTO> = assignments
+            .split_off(kept)
+            .into_iter()
+            .map(|assignment| assignment.dto)
+            .collect();
+        for dto in requeued.iter().rev() {
+            self.orders.push_front(dto.clone());
+        }
+        for assignment in assignments.iter_mut() {
+            assignment.expiration = now + processing_deadline(&assignment.dto);
+        }
+        let resumed = assignments
+            .iter()
+            .map(|assignment| assignment.dto.clone())
+            .collect();
+        self.working.insert(addr, assignments);
+        for _ in kept..capacity {
+            self.free_slots.push_back(addr);
+        }
+        Registration::Restarted { resumed, requeued }
     }
 
-    fn wait_for_sender_work_pendings(&self) {
-        let (lock, cvar) = &self.pending_send_works.as_ref();
-        let guard = lock.lock().unwrap();
-        let _result = cvar.wait_while(guard, |guard| guard.is_empty()).unwrap();
-    }
-
-    fn checking_work(&mut self) {
-        self.leader_flag.wait_for_leader();
-        loop {
-            self.wait_for_works_pendings();
-            let mut pending = self.working_pending.0.lock().unwrap();
-            let now = Instant::now();
-            let mut i = 0;
-            while i < pending.len() {
-                if pending[i].expiration <= now {
-                    let mut list = self.working_list.lock().unwrap();
-                    let order_incomplete = list.get(&pending[i].item_type);
-                    let mut order_list = self.orders_list.0.lock().unwrap();
-                    let cvar = &self.orders_list.1;
-                    if let Some(order) = order_incomplete {
-                        if order.id_order == pending[i].n_order {
-                            Logger.log(
-                                LogLevel::LeaderInfo,
-                                format!("Order {} is incomplete, return to orders list, Robot failure in {}", order.id_order, pending[i].item_type).as_str(),
-                            );
-                            order_list.insert(order.id_order, order.clone());
-                            list.remove(&pending[i].item_type);
-                            cvar.notify_all();
-                        }
-                    };
-                    pending.remove(i);
-                    break;
-                } else {
-                    i += 1;
+    /// Asigna el proximo pedido al proximo lugar libre, si hay ambos.
+    pub fn next_assignment(&mut self, now: Instant) -> Option<(SocketAddr, DTO)> {
+        if self.orders.is_empty() {
+            return None;
+        }
+        let addr = self.free_slots.pop_front()?;
+        let dto = self.orders.pop_front()?;
+        let expiration = now + processing_deadline(&dto);
+        self.working.entry(addr).or_default().push(Assignment {
+            dto: dto.clone(),
+            expiration,
+        });
+        Some((addr, dto))
+    }
+
+    /// Marca como terminado un pedido de un robot, le libera un lugar y olvida
+    /// la reserva de stock del pedido. Devuelve false si el pedido ya no
+    /// estaba asignado (por ejemplo, un Availability repetido).
+    pub fn finish_order(&mut self, addr: SocketAddr, key: OrderKey) -> bool {
+        if let Some(assignments) = self.working.get_mut(&addr) {
+            if let Some(pos) = assignments
+                .iter()
+                .position(|assignment| assignment.dto.key() == key)
+            {
+                assignments.remove(pos);
+                self.reserved.remove(&key);
+                if !self.draining.contains(&addr) {
+                    self.free_slots.push_back(addr);
                 }
+                return true;
             }
         }
+        false
     }
 
-    fn wait_for_works_pendings(&self) {
-        let (lock, cvar) = &self.working_pending.as_ref();
-        let guard = lock.lock().unwrap();
-        let _result = cvar.wait_while(guard, |guard| guard.is_empty()).unwrap();
+    /// Extiende el plazo de un pedido en curso cuando el robot informa progreso.
+    pub fn extend_deadline(
+        &mut self,
+        addr: SocketAddr,
+        key: OrderKey,
+        extension: Duration,
+        now: Instant,
+    ) -> bool {
+        let extension = extension.min(MAX_EXTENSION);
+        match self.working.get_mut(&addr).and_then(|assignments| {
+            assignments
+                .iter_mut()
+                .find(|assignment| assignment.dto.key() == key)
+        }) {
+            Some(assignment) => {
+                assignment.expiration = assignment.expiration.max(now + extension);
+                true
+            }
+            None => false,
+        }
     }
 
-    pub fn wait_for_socket(&mut self) -> UdpSocket {
-        let (lock, cvar) = (&self.socket_leader, &self.condvar_socket);
-        let sock_guard = lock.lock().unwrap();
-        let _socket = cvar
-            .wait_while(sock_guard, |sock_guard| sock_guard.is_none())
-            .unwrap();
-        _socket.as_ref().unwrap().try_clone().unwrap()
+    /// Deja de asignarle pedidos a un robot que se va a retirar y devuelve a
+    /// la cola los pedidos que el robot no llego a empezar.
+    pub fn drain_robot(&mut self, addr: SocketAddr, handed_back: &[OrderKey]) -> Vec<DTO> {
+        self.draining.insert(addr);
+        self.free_slots.retain(|slot| *slot != addr);
+        self.requeue(addr, |assignment| {
+            handed_back.contains(&assignment.dto.key())
+        })
     }
 
-    pub fn receiver(&mut self) {
-        self.leader_flag.wait_for_leader();
+    /// Saca a un robot del reparto y devuelve a la cola todo lo que tenia
+    /// asignado.
+    pub fn remove_robot(&mut self, addr: SocketAddr) -> Vec<DTO> {
+        self.robots.remove(&addr);
+        self.incarnations.remove(&addr);
+        self.draining.remove(&addr);
+        self.free_slots.retain(|slot| *slot != addr);
+        let requeued = self.requeue(addr, |_| true);
+        self.working.remove(&addr);
+        requeued
+    }
 
-        let socket = self.wait_for_socket();
+    fn requeue<F>(&mut self, addr: SocketAddr, condition: F) -> Vec<DTO>
+    where
+        F: Fn(&Assignment) -> bool,
+    {
+        let requeued: Vec<DTO> = match self.working.get_mut(&addr) {
+            Some(assignments) => {
+                let (requeued, kept): (Vec<Assignment>, Vec<Assignment>) = assignments
+                    .drain(..)
+                    .partition(|assignment| condition(assignment));
+                *assignments = kept;
+                requeued
+                    .into_iter()
+                    .map(|assignment| assignment.dto)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        for dto in requeued.iter().rev() {
+            self.orders.push_front(dto.clone());
+        }
+        requeued
+    }
 
-        Logger.log(
-            LogLevel::LeaderInfo,
-            format!("Receiver socket {}", socket.local_addr().unwrap()).as_str(),
-        );
+    /// Pedidos cuyo robot se paso del plazo.
+    pub fn expired(&self, now: Instant) -> Vec<(SocketAddr, OrderKey)> {
+        self.working
+            .iter()
+            .flat_map(|(addr, assignments)| {
+                assignments
+                    .iter()
+                    .filter(|assignment| assignment.expiration <= now)
+                    .map(move |assignment| (*addr, assignment.dto.key()))
+            })
+            .collect()
+    }
 
-        loop {
-            let mut buffer = [0; 1024];
-            match socket.recv_from(&mut buffer) {
-                Ok((size, addr)) => {
-                    let content = String::from_utf8_lossy(&buffer[..size]).to_string();
-                    if content.starts_with("Order:") {
-                        let msg: String = content[6..].parse().unwrap();
-                        self.add_order(msg.clone(), addr);
-                    } else if content.starts_with("Ack:") {
-                        let msg: String = content[4..].parse().unwrap();
-                        self.resolve_ack(msg.clone(), addr);
-                    }
-                }
-                Err(e) => {
+    /// Devuelve a la cola un pedido cuyo robot se paso del plazo y le libera
+    /// el lugar al robot: si de verdad se cayo, su baja le saca los lugares.
+    /// La reserva de stock se olvida porque el robot que lo retome la vuelve
+    /// a pedir.
+    pub fn expire_order(&mut self, addr: SocketAddr, key: OrderKey) -> Option<DTO> {
+        let assignments = self.working.get_mut(&addr)?;
+        let pos = assignments
+            .iter()
+            .position(|assignment| assignment.dto.key() == key)?;
+        let dto = assignments.remove(pos).dto;
+        self.reserved.remove(&key);
+        self.orders.push_front(dto.clone());
+        if self.robots.contains_key(&addr) && !self.draining.contains(&addr) {
+            self.free_slots.push_back(addr);
+        }
+        Some(dto)
+    }
+
+    /// Los pedidos en curso arrancan con un plazo nuevo, por ejemplo cuando
+    /// una replica pasa a ser lider.
+    pub fn restart_deadlines(&mut self, now: Instant) {
+        for assignment in self.working.values_mut().flatten() {
+            assignment.expiration = now + processing_deadline(&assignment.dto);
+        }
+    }
+
+    /// Reserva el stock de un pedido una sola vez: si el pedido se vuelve a
+    /// pedir (por ejemplo, despues de un cambio de lider) se repite el
+    /// resultado anterior.
+    pub fn reserve_stock(&mut self, key: OrderKey, ice_creams: &[String], amount: f64) -> bool {
+        if let Some(result) = self.reserved.get(&key) {
+            return *result;
+        }
+        let result = self.use_stock(ice_creams, amount);
+        self.reserved.insert(key, result);
+        result
+    }
+
+    pub fn use_stock(&mut self, ice_creams: &[String], amount: f64) -> bool {
+        let mut result = false;
+        for ice_cream in ice_creams.iter() {
+            if let Some(ice) = self.stock.get_mut(ice_cream) {
+                result = ice.use_stock(amount);
+                if !result {
                     Logger.log(
                         LogLevel::Error,
-                        format!("Error receive order {}", e).as_str(),
+                        format!("Not enough stock for flavour {}", ice_cream).as_str(),
                     );
                     break;
                 }
             }
         }
+        result
+    }
+}
+
+/// Actor que cumple el rol de lider: recibe pedidos de las interfaces en el
+/// puerto de lider del robot, los reparte entre los robots y administra el stock.
+/// Todo lo que manda lleva la epoca de su mandato.
+///
+/// Con Raft hay uno en cada robot: todos aplican los comandos confirmados del
+/// log y solo el que lidera atiende pedidos y les escribe a los robots.
+pub struct LeaderOrderProcessing {
+    socket: Arc<UdpSocket>,
+    ack_manager: AckManager,
+    epoch: u64,
+    state: LeaderState,
+    replication: Option<Recipient<Propose>>,
+    leading: bool,
+}
+
+impl LeaderOrderProcessing {
+    pub fn new(epoch: u64, addr: SocketAddr) -> io::Result<LeaderOrderProcessing> {
+        LeaderOrderProcessing::with_state(LeaderState::new(), epoch, addr)
+    }
+
+    /// Lider que continua el trabajo de otro a partir de su estado, con una
+    /// epoca nueva.
+    pub fn from_snapshot(
+        snapshot: LeaderSnapshot,
+        epoch: u64,
+        addr: SocketAddr,
+    ) -> io::Result<LeaderOrderProcessing> {
+        let state = LeaderState::from_snapshot(snapshot, Instant::now());
+        LeaderOrderProcessing::with_state(state, epoch, addr)
+    }
+
+    /// Replica del estado del lider para el modo Raft. Arranca siguiendo y
+    /// manda sus cambios a `replication` para que pasen por el log.
+    pub fn replica(
+        addr: SocketAddr,
+        replication: Recipient<Propose>,
+    ) -> io::Result<LeaderOrderProcessing> {
+        let mut replica = LeaderOrderProcessing::with_state(LeaderState::new(), 0, addr)?;
+        replica.replication = Some(replication);
+        replica.leading = false;
+        Ok(replica)
+    }
+
+    fn with_state(
+        state: LeaderState,
+        epoch: u64,
+        addr: SocketAddr,
+    ) -> io::Result<LeaderOrderProcessing> {
+        let socket = udp::into_async(std::net::UdpSocket::bind(addr)?)?;
+        Ok(LeaderOrderProcessing {
+            ack_manager: AckManager::new(Arc::clone(&socket)),
+            socket,
+            epoch,
+            state,
+            replication: None,
+            leading: true,
+        })
+    }
+
+    fn sender(&self, msg: &str, addr: SocketAddr) {
+        match udp::send_message(&self.socket, msg, addr) {
+            Ok(_) => Logger.log(
+                LogLevel::LeaderInfo,
+                format!("Message send to addr {}", addr).as_str(),
+            ),
+            Err(e) => Logger.log(
+                LogLevel::Error,
+                format!("Error send message to addr {} with error: {}", addr, e).as_str(),
+            ),
+        }
+    }
+
+    /// Sin Raft el comando se aplica enseguida; con Raft se aplica cuando
+    /// vuelve confirmado por el log.
+    fn execute(&mut self, command: Command) {
+        match &self.replication {
+            Some(replication) => {
+                if replication.do_send(Propose { command }).is_err() {
+                    Logger.log(LogLevel::Error, "Could not propose a command to the log");
+                }
+            }
+            None => self.apply(command),
+        }
     }
 
-    fn add_order(&mut self, data: String, addr: SocketAddr) {
-        let dto = serde_json::from_str::<DTO>(data.as_str()).unwrap();
+    /// Aplica un comando al estado. Los mensajes a robots e interfaces solo
+    /// los manda el lider.
+    fn apply(&mut self, command: Command) {
+        match command {
+            Command::AddOrder { dto, interface } => {
+                let id_order = dto.id_order;
+                let added = self.state.add_order(dto);
+                if self.leading {
+                    self.sender(format!("Ack:Order:{}", id_order).as_str(), interface);
+                }
+                if !added {
+                    return;
+                }
+            }
+            Command::AddRobot {
+                addr,
+                capacity,
+                incarnation,
+            } => match self
+                .state
+                .add_robot(addr, capacity, incarnation, Instant::now())
+            {
+                Registration::New => {
+                    self.log(format!("Robot {} joins with capacity {}", addr, capacity))
+                }
+                Registration::Known => return,
+                Registration::Restarted { resumed, requeued } => {
+                    let ids = |orders: &[DTO]| -> Vec<String> {
+                        orders.iter().map(|dto| dto.key().to_string()).collect()
+                    };
+                    self.log(format!(
+                        "Robot {} restarted, resuming orders {:?} and returning {:?} to the list",
+                        addr,
+                        ids(&resumed),
+                        ids(&requeued)
+                    ));
+                    for dto in resumed {
+                        self.send_work(addr, &dto);
+                    }
+                }
+            },
+            Command::FinishOrder { addr, key } => {
+                if !self.state.finish_order(addr, key) {
+                    return;
+                }
+            }
+            Command::ExpireOrder { addr, key } => {
+                if self.state.expire_order(addr, key).is_none() {
+                    return;
+                }
+                self.log(format!(
+                    "Order {} is incomplete, return to orders list, Robot failure in {}",
+                    key, addr
+                ));
+            }
+            Command::DrainRobot { addr, handed_back } => {
+                let requeued = self.state.drain_robot(addr, &handed_back);
+                self.log(format!(
+                    "Robot {} is draining, {} orders returned to the list",
+                    addr,
+                    requeued.len()
+                ));
+            }
+            Command::RemoveRobot { addr } => {
+                let requeued = self.state.remove_robot(addr);
+                self.log(format!(
+                    "Robot {} left, {} orders returned to the list",
+                    addr,
+                    requeued.len()
+                ));
+            }
+            Command::UseStock {
+                key,
+                ice_creams,
+                amount,
+                addr,
+            } => {
+                let result = self.state.reserve_stock(key, &ice_creams, amount);
+                if self.leading {
+                    let reply = format!("StockResult:{},{},{}", key, result, self.epoch);
+                    self.sender(reply.as_str(), addr);
+                    self.ack_manager.add(
+                        Ack::new(addr, reply, "StockResult".to_string()),
+                        Duration::from_secs(5),
+                    );
+                }
+                return;
+            }
+        }
+        self.dispatch();
+    }
+
+    fn log(&self, message: String) {
+        if self.leading {
+            Logger.log(LogLevel::LeaderInfo, message.as_str());
+        }
+    }
+
+    fn add_order(&mut self, data: &str, addr: SocketAddr) {
+        // Una replica que no lidera no toma pedidos: la interfaz vuelve a
+        // preguntar quien es el lider
+        if !self.leading {
+            return;
+        }
+        let dto = match serde_json::from_str::<DTO>(data) {
+            Ok(dto) => dto,
+            Err(e) => {
+                Logger.log(
+                    LogLevel::Error,
+                    format!("Invalid order from {}: {}", addr, e).as_str(),
+                );
+                return;
+            }
+        };
         Logger.log(
             LogLevel::LeaderInfo,
             format!(
@@ -234,151 +746,560 @@ impl LeaderOrderProcessing {
             )
             .as_str(),
         );
-        self.sender("Ack:Order", addr);
-        let mut list = self.orders_list.0.lock().unwrap();
-        list.insert(dto.id_order, dto);
-        self.orders_list.1.notify_all();
-    }
-
-    fn resolve_ack(&mut self, msg: String, addr: SocketAddr) {
-        if let Some(ack_manager) = self.ack_manager.lock().unwrap().as_mut() {
-            match msg.as_str() {
-                "Work" => ack_manager.remove(msg.clone(), addr),
-                "StockResult" => ack_manager.remove(msg.clone(), addr),
-                _ => {
-                    // Handle other cases here
-                }
+        self.execute(Command::AddOrder {
+            dto,
+            interface: addr,
+        });
+    }
+
+    fn resolve_ack(&mut self, msg: &str, addr: SocketAddr) {
+        match msg {
+            m if m.starts_with("Work:") => self.ack_manager.remove(msg.to_string(), addr),
+            "StockResult" => self.ack_manager.remove(msg.to_string(), addr),
+            _ => {
+                // Handle other cases here
             }
         }
     }
 
-    fn send_work(&self, addr: SocketAddr) {
-        let dto_opt = self.get_next_order();
-        if let Some(dto) = dto_opt {
-            self.asign_work(addr, dto.clone());
-            Logger.log(
-                LogLevel::Work,
-                format!("Send Order {} to Robot {} ", dto.id_order, addr).as_str(),
-            );
-            let msg = format!("Work:{}", dto.serialize().as_str());
-            self.sender(msg.clone().as_str(), addr);
-            if let Some(ack_manager) = self.ack_manager.lock().unwrap().as_mut() {
-                ack_manager.add(
-                    Ack::new(addr, msg.clone().to_string(), "Work".to_string()),
-                    Duration::from_secs(5),
-                );
-            }
+    /// Reparte pedidos mientras haya pedidos en cola y robots con lugar. Con
+    /// Raft todas las replicas reparten igual, pero solo el lider manda el
+    /// trabajo.
+    fn dispatch(&mut self) {
+        while let Some((addr, dto)) = self.state.next_assignment(Instant::now()) {
+            self.send_work(addr, &dto);
         }
     }
 
-    fn sender(&self, msg: &str, addr: SocketAddr) {
-        let socket_guard = self.socket_leader.lock().unwrap();
-        if let Some(socket) = &*socket_guard {
-            match socket.send_to(msg.as_bytes(), addr) {
-                Ok(_) => Logger.log(
-                    LogLevel::LeaderInfo,
-                    format!("Message send to addr {}", addr).as_str(),
-                ),
-                Err(e) => Logger.log(
-                    LogLevel::Error,
-                    format!("Error send message to addr {} with error: {}", addr, e).as_str(),
-                ),
-            }
-        } else {
-            Logger.log(LogLevel::Error, "No hay socket para enviar orden");
+    fn send_work(&mut self, addr: SocketAddr, dto: &DTO) {
+        if !self.leading {
+            return;
         }
+        Logger.log(
+            LogLevel::LeaderInfo,
+            format!(
+                "Order {} assigned to {} with deadline of {:?}",
+                dto.key(),
+                addr,
+                processing_deadline(dto)
+            )
+            .as_str(),
+        );
+        Logger.log(
+            LogLevel::Work,
+            format!("Send Order {} to Robot {} ", dto.key(), addr).as_str(),
+        );
+        let msg = format!("Work:{};{}", self.epoch, dto.serialize().as_str());
+        self.sender(msg.as_str(), addr);
+        self.ack_manager.add(
+            Ack::new(addr, msg, format!("Work:{}", dto.key())),
+            Duration::from_secs(5),
+        );
     }
 
-    pub fn wait_for_orders(&self) {
-        let (lock, cvar) = &self.orders_list.as_ref();
-        let list = lock.lock().unwrap();
-        let _list_empty = cvar.wait_while(list, |orders| orders.is_empty()).unwrap();
+    fn check_deadlines(&mut self) {
+        if !self.leading {
+            return;
+        }
+        for (addr, key) in self.state.expired(Instant::now()) {
+            self.execute(Command::ExpireOrder { addr, key });
+        }
     }
+}
 
-    fn add_addr_sender_work(&mut self, addr: SocketAddr) {
-        let (lock, cvar) = &self.pending_send_works.as_ref();
-        let mut list_pending_send = lock.lock().unwrap();
-        list_pending_send.push(addr);
-        cvar.notify_all();
+impl Actor for LeaderOrderProcessing {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let socket = Arc::clone(&self.socket);
+        if let Ok(addr) = socket.local_addr() {
+            Logger.log(
+                LogLevel::LeaderInfo,
+                format!("Receiver socket {}", addr).as_str(),
+            );
+        }
+        let actor_addr = ctx.address();
+        // La lectura queda atada al contexto para que al frenar el actor se
+        // suelte el socket y el puerto quede libre para otro mandato
+        let receiver = async move {
+            let mut buffer = [0; 1024];
+            loop {
+                match socket.recv_from(&mut buffer).await {
+                    Ok((size, addr)) => {
+                        let content = String::from_utf8_lossy(&buffer[..size]).to_string();
+                        actor_addr.do_send(Msg { content, addr });
+                    }
+                    Err(e) => {
+                        Logger.log(
+                            LogLevel::Error,
+                            format!("Error receive order {}", e).as_str(),
+                        );
+                        break;
+                    }
+                }
+            }
+        };
+        ctx.spawn(receiver.into_actor(self));
+        ctx.run_interval(WATCHDOG_INTERVAL, |act, _ctx| act.check_deadlines());
     }
+}
 
-    pub fn send_work_to_robot(&mut self, addr: SocketAddr) {
-        // self.wait_for_orders();
-        let (list_orders, _) = &self.orders_list.as_ref();
-        if list_orders.lock().unwrap().is_empty() {
-            self.add_addr_sender_work(addr);
-        } else {
-            self.send_work(addr);
+impl Handler<Msg> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: Msg, _ctx: &mut Self::Context) {
+        if let Some(data) = msg.content.strip_prefix("Order:") {
+            self.add_order(data, msg.addr);
+        } else if let Some(data) = msg.content.strip_prefix("Ack:") {
+            self.resolve_ack(data, msg.addr);
         }
     }
+}
 
-    pub fn asign_work(&self, addr: SocketAddr, content: DTO) {
-        let mut list = self.working_list.lock().unwrap();
-        let id_order = content.id_order;
-        list.insert(addr, content);
-        let addr_work = addr;
-        self.working_pending.0.lock().unwrap().push(TimedItem::new(
-            addr_work,
-            id_order,
-            Duration::new(5, 0),
-        ));
+impl Handler<HandOver> for LeaderOrderProcessing {
+    type Result = MessageResult<HandOver>;
+
+    fn handle(&mut self, _msg: HandOver, ctx: &mut Self::Context) -> Self::Result {
+        Logger.log(LogLevel::LeaderInfo, "Handing over the leader state");
+        self.ack_manager.stop();
+        ctx.stop();
+        let mut snapshot = self.state.snapshot();
+        snapshot.epoch = self.epoch;
+        MessageResult(snapshot)
     }
+}
 
-    pub fn get_next_order(&self) -> Option<DTO> {
-        let mut list = self.orders_list.0.lock().unwrap();
-        if list.is_empty() {
-            None
-        } else {
-            let k = *list.keys().next().unwrap();
-            let dto = list.get(&k).cloned();
-            list.remove(&k);
-            dto
+impl Handler<Apply> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: Apply, _ctx: &mut Self::Context) {
+        for command in msg.commands {
+            self.apply(command);
         }
     }
+}
+
+impl Handler<Lead> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: Lead, _ctx: &mut Self::Context) {
+        Logger.log(
+            LogLevel::LeaderInfo,
+            format!("Replica leading at epoch {}", msg.epoch).as_str(),
+        );
+        self.epoch = msg.epoch;
+        self.leading = true;
+        // Los plazos que corrian en otro lider no se conocen
+        self.state.restart_deadlines(Instant::now());
+    }
+}
+
+impl Handler<Follow> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Follow, _ctx: &mut Self::Context) {
+        self.leading = false;
+    }
+}
+
+impl Handler<RegisterRobot> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterRobot, _ctx: &mut Self::Context) {
+        self.execute(Command::AddRobot {
+            addr: msg.addr,
+            capacity: msg.capacity,
+            incarnation: msg.incarnation,
+        });
+    }
+}
+
+impl Handler<OrderFinished> for LeaderOrderProcessing {
+    type Result = ();
 
-    pub fn finish_order(&mut self, addr: SocketAddr) {
-        let mut list = self.working_list.lock().unwrap();
+    fn handle(&mut self, msg: OrderFinished, _ctx: &mut Self::Context) {
         Logger.log(
             LogLevel::LeaderInfo,
-            format!("Robot in direction {} finish work", addr).as_str(),
+            format!("Robot in direction {} finish order {}", msg.addr, msg.key).as_str(),
         );
-        let mut pending = self.working_pending.0.lock().unwrap();
-        if let Some(order) = list.get(&addr) {
-            let id_order = order.id_order;
-            pending.retain(|item| item.item_type != addr && item.n_order != id_order);
-            list.remove(&addr);
+        self.execute(Command::FinishOrder {
+            addr: msg.addr,
+            key: msg.key,
+        });
+    }
+}
+
+impl Handler<DrainRobot> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: DrainRobot, _ctx: &mut Self::Context) {
+        self.execute(Command::DrainRobot {
+            addr: msg.addr,
+            handed_back: msg.handed_back,
+        });
+    }
+}
+
+impl Handler<RemoveRobot> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveRobot, _ctx: &mut Self::Context) {
+        self.execute(Command::RemoveRobot { addr: msg.addr });
+    }
+}
+
+impl Handler<ExtendDeadline> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExtendDeadline, _ctx: &mut Self::Context) {
+        // Los plazos son locales del lider: no pasan por el log
+        if self
+            .state
+            .extend_deadline(msg.addr, msg.key, msg.extension, Instant::now())
+        {
+            Logger.log(
+                LogLevel::LeaderInfo,
+                format!(
+                    "Robot {} reported progress on order {}, deadline extended {:?}",
+                    msg.addr, msg.key, msg.extension
+                )
+                .as_str(),
+            );
         }
     }
+}
 
-    pub fn update_leader(&mut self) {
-        self.leader_flag.set_leader();
-        let new_socket = UdpSocket::bind("127.0.0.1:5000").unwrap();
-        let mut ack_manager = self.ack_manager.lock().unwrap();
-        *ack_manager = Some(AckManager::new(new_socket.try_clone().unwrap()));
-        let mut socket_guard = self.socket_leader.lock().unwrap();
-        *socket_guard = Some(new_socket);
-        self.condvar_socket.notify_all();
+impl Handler<ReserveStock> for LeaderOrderProcessing {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReserveStock, _ctx: &mut Self::Context) {
+        // El stock solo se toca con pedidos de la epoca de este lider: uno
+        // mas viejo viene de otro mandato y uno mas nuevo indica que este
+        // lider ya fue reemplazado
+        if !self.leading || msg.epoch != self.epoch {
+            Logger.log(
+                LogLevel::Error,
+                format!(
+                    "Rejecting stock for order {} from epoch {}, current epoch is {}",
+                    msg.key, msg.epoch, self.epoch
+                )
+                .as_str(),
+            );
+            return;
+        }
+        self.execute(Command::UseStock {
+            key: msg.key,
+            ice_creams: msg.ice_creams,
+            amount: msg.amount,
+            addr: msg.addr,
+        });
     }
+}
 
-    pub fn use_stock(&mut self, ice_creams: &[String], amount: f64, addr: SocketAddr) {
-        let mut result = false;
-        let mut stock = self.stock.write().unwrap();
-        for ice_cream in ice_creams.iter() {
-            if let Some(ice) = stock.get_mut(ice_cream) {
-                result = ice.use_stock(amount);
-                if !result {
-                    Logger.log(
-                        LogLevel::Error,
-                        format!("Not enough stock for flavour {}", ice_cream).as_str(),
-                    );
-                    break;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn robot(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn order(interface: usize, id: usize) -> DTO {
+        DTO {
+            id_order: id,
+            id_interface: interface,
+            ice_creams: vec!["Chocolate".to_string()],
+            size_order: 0.5,
+            cash_card: 0,
+            total_amount: 850,
+            owner: SocketAddr::from(([127, 0, 0, 1], 9000 + interface as u16)),
+            takeover: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    fn keys(orders: &[DTO]) -> Vec<OrderKey> {
+        orders.iter().map(|dto| dto.key()).collect()
+    }
+
+    /// Estado con los robots dados (puerto, capacidad) y los pedidos de la
+    /// interfaz 1 con los ids dados, todavia sin asignar.
+    fn state(robots: &[(u16, usize)], orders: &[usize]) -> (LeaderState, Instant) {
+        let now = Instant::now();
+        let mut state = LeaderState::new();
+        for (port, capacity) in robots {
+            state.add_robot(robot(*port), *capacity, 1, now);
+        }
+        for id in orders {
+            assert!(state.add_order(order(1, *id)));
+        }
+        (state, now)
+    }
+
+    fn assign_all(state: &mut LeaderState, now: Instant) -> Vec<(SocketAddr, OrderKey)> {
+        std::iter::from_fn(|| state.next_assignment(now))
+            .map(|(addr, dto)| (addr, dto.key()))
+            .collect()
+    }
+
+    #[test]
+    fn add_order_ignores_repeats_of_the_same_interface_only() {
+        let (mut state, now) = state(&[(6001, 1)], &[5]);
+        assert!(!state.add_order(order(1, 5)));
+        // El mismo id de otra interfaz es otro pedido
+        assert!(state.add_order(order(2, 5)));
+
+        assert_eq!(
+            assign_all(&mut state, now),
+            vec![(robot(6001), OrderKey::new(1, 5))]
+        );
+        // Tambien se reconoce mientras esta en curso
+        assert!(!state.add_order(order(1, 5)));
+        assert!(!state.add_order(order(2, 5)));
+    }
+
+    #[test]
+    fn next_assignment_fills_free_slots_in_order() {
+        let (mut state, now) = state(&[], &[1, 2, 3, 4]);
+        assert!(state.next_assignment(now).is_none());
+
+        state.add_robot(robot(6001), 2, 1, now);
+        state.add_robot(robot(6002), 1, 1, now);
+        assert_eq!(
+            assign_all(&mut state, now),
+            vec![
+                (robot(6001), OrderKey::new(1, 1)),
+                (robot(6001), OrderKey::new(1, 2)),
+                (robot(6002), OrderKey::new(1, 3)),
+            ]
+        );
+        assert_eq!(keys(&state.snapshot().orders), vec![OrderKey::new(1, 4)]);
+    }
+
+    #[test]
+    fn a_restarted_robot_resumes_up_to_its_new_capacity() {
+        let (mut state, now) = state(&[(6001, 3)], &[1, 2, 3, 4]);
+        assign_all(&mut state, now);
+        state.drain_robot(robot(6001), &[]);
+        assert!(matches!(
+            state.add_robot(robot(6001), 3, 1, now),
+            Registration::Known
+        ));
+
+        // Vuelve a anunciarse con otra encarnacion y menos capacidad
+        let deadline = processing_deadline(&order(1, 1));
+        let later = now + deadline;
+        match state.add_robot(robot(6001), 2, 2, later) {
+            Registration::Restarted { resumed, requeued } => {
+                assert_eq!(
+                    keys(&resumed),
+                    vec![OrderKey::new(1, 1), OrderKey::new(1, 2)]
+                );
+                assert_eq!(keys(&requeued), vec![OrderKey::new(1, 3)]);
             }
+            other => panic!("expected a restart, got {:?}", other),
         }
-        self.sender(format!("StockResult:{}", result).as_str(), addr);
-        self.ack_manager.lock().unwrap().as_mut().unwrap().add(
-            Ack::new(addr, "StockResult".to_string(), "StockResult".to_string()),
-            Duration::from_secs(5),
+        assert_eq!(
+            keys(&state.snapshot().orders),
+            vec![OrderKey::new(1, 3), OrderKey::new(1, 4)]
+        );
+        // Lo retomado tiene plazo nuevo y el robot ya no se esta retirando
+        assert!(state.expired(later).is_empty());
+        assert!(state.next_assignment(later).is_none());
+        assert!(state.finish_order(robot(6001), OrderKey::new(1, 1)));
+        assert_eq!(
+            assign_all(&mut state, later),
+            vec![(robot(6001), OrderKey::new(1, 3))]
+        );
+    }
+
+    #[test]
+    fn finish_order_frees_the_robot_slot_once() {
+        let (mut state, now) = state(&[(6001, 1)], &[1, 2]);
+        assign_all(&mut state, now);
+
+        assert!(!state.finish_order(robot(6001), OrderKey::new(2, 1)));
+        assert!(!state.finish_order(robot(6002), OrderKey::new(1, 1)));
+        assert!(state.finish_order(robot(6001), OrderKey::new(1, 1)));
+        // Un Availability repetido no libera otro lugar
+        assert!(!state.finish_order(robot(6001), OrderKey::new(1, 1)));
+
+        assert_eq!(
+            assign_all(&mut state, now),
+            vec![(robot(6001), OrderKey::new(1, 2))]
+        );
+    }
+
+    #[test]
+    fn expire_order_requeues_first_and_frees_the_slot() {
+        let (mut state, now) = state(&[(6001, 1)], &[1, 2]);
+        assign_all(&mut state, now);
+        let deadline = processing_deadline(&order(1, 1));
+        assert!(state.expired(now).is_empty());
+        assert!(state.extend_deadline(robot(6001), OrderKey::new(1, 1), deadline * 2, now));
+        assert!(state.expired(now + deadline).is_empty());
+
+        let later = now + deadline * 2;
+        assert_eq!(
+            state.expired(later),
+            vec![(robot(6001), OrderKey::new(1, 1))]
+        );
+        let expired = state.expire_order(robot(6001), OrderKey::new(1, 1));
+        assert_eq!(expired.map(|dto| dto.key()), Some(OrderKey::new(1, 1)));
+        assert!(state
+            .expire_order(robot(6001), OrderKey::new(1, 1))
+            .is_none());
+        // El lugar vuelve a estar libre y el pedido vencido sale primero
+        state.add_robot(robot(6002), 1, 1, later);
+        assert_eq!(
+            assign_all(&mut state, later),
+            vec![
+                (robot(6001), OrderKey::new(1, 1)),
+                (robot(6002), OrderKey::new(1, 2)),
+            ]
+        );
+        // Terminarlo libera un solo lugar aunque el Availability se repita
+        assert!(state.finish_order(robot(6001), OrderKey::new(1, 1)));
+        assert!(!state.finish_order(robot(6001), OrderKey::new(1, 1)));
+        assert!(state.next_assignment(later).is_none());
+        assert_eq!(state.snapshot().robots.len(), 2);
+
+        // Si el robot se va, la baja le saca el lugar
+        assert!(state.add_order(order(1, 3)));
+        state.remove_robot(robot(6001));
+        assert!(state.next_assignment(later).is_none());
+    }
+
+    #[test]
+    fn reserve_stock_repeats_the_result_until_the_order_ends() {
+        let (mut state, now) = state(&[(6001, 2)], &[1, 2]);
+        assign_all(&mut state, now);
+        let chocolate = ["Chocolate".to_string()];
+
+        assert!(state.reserve_stock(OrderKey::new(1, 1), &chocolate, 6.0));
+        // Un UseStock repetido no vuelve a descontar
+        assert!(state.reserve_stock(OrderKey::new(1, 1), &chocolate, 6.0));
+        // El mismo id de otra interfaz es otra reserva
+        assert!(!state.reserve_stock(OrderKey::new(2, 1), &chocolate, 6.0));
+        assert!(state.reserve_stock(OrderKey::new(1, 2), &chocolate, 3.0));
+
+        assert!(state.finish_order(robot(6001), OrderKey::new(1, 1)));
+        assert!(!state.reserve_stock(OrderKey::new(1, 1), &chocolate, 6.0));
+
+        assert!(state
+            .expire_order(robot(6001), OrderKey::new(1, 2))
+            .is_some());
+        assert!(state
+            .snapshot()
+            .reserved
+            .iter()
+            .all(|(key, _)| key.order != 2));
+        assert!(state.reserve_stock(OrderKey::new(1, 2), &chocolate, 1.0));
+    }
+
+    #[test]
+    fn drain_robot_hands_back_only_unstarted_orders() {
+        let (mut state, now) = state(&[(6001, 3)], &[1, 2, 3, 4]);
+        assign_all(&mut state, now);
+
+        let handed_back = [OrderKey::new(1, 2), OrderKey::new(1, 3)];
+        let requeued = state.drain_robot(robot(6001), &handed_back);
+        assert_eq!(keys(&requeued), handed_back.to_vec());
+        assert_eq!(
+            keys(&state.snapshot().orders),
+            vec![
+                OrderKey::new(1, 2),
+                OrderKey::new(1, 3),
+                OrderKey::new(1, 4)
+            ]
+        );
+        // Termina lo que tenia empezado pero no recibe nada nuevo
+        assert!(state.finish_order(robot(6001), OrderKey::new(1, 1)));
+        assert!(state.next_assignment(now).is_none());
+    }
+
+    #[test]
+    fn remove_robot_requeues_everything_it_had() {
+        let (mut state, now) = state(&[(6001, 2), (6002, 1)], &[1, 2, 3, 4]);
+        assign_all(&mut state, now);
+
+        let requeued = state.remove_robot(robot(6001));
+        assert_eq!(
+            keys(&requeued),
+            vec![OrderKey::new(1, 1), OrderKey::new(1, 2)]
+        );
+        assert!(!state.finish_order(robot(6001), OrderKey::new(1, 1)));
+
+        assert!(state.finish_order(robot(6002), OrderKey::new(1, 3)));
+        assert_eq!(
+            assign_all(&mut state, now),
+            vec![(robot(6002), OrderKey::new(1, 1))]
+        );
+    }
+
+    #[test]
+    fn from_snapshot_keeps_queue_assignments_slots_and_stock() {
+        let (mut state, now) = state(&[(6001, 2), (6002, 1), (6003, 1)], &[1, 2, 3, 4, 5]);
+        assign_all(&mut state, now);
+        state.drain_robot(robot(6003), &[]);
+        assert!(state.use_stock(&["Chocolate".to_string()], 9.0));
+
+        let mut restored = LeaderState::from_snapshot(state.snapshot(), now);
+        // Lo que estaba en cola o en curso se sigue reconociendo
+        for id in 1..=5 {
+            assert!(!restored.add_order(order(1, id)), "order {}", id);
+        }
+        assert_eq!(keys(&restored.snapshot().orders), vec![OrderKey::new(1, 5)]);
+        assert!(!restored.use_stock(&["Chocolate".to_string()], 2.0));
+        assert!(restored.use_stock(&["Chocolate".to_string()], 1.0));
+
+        // Los robots llenos siguen llenos y el que se retira no recibe nada
+        assert!(restored.next_assignment(now).is_none());
+        assert!(restored.finish_order(robot(6003), OrderKey::new(1, 4)));
+        assert!(restored.next_assignment(now).is_none());
+        assert!(restored.finish_order(robot(6001), OrderKey::new(1, 1)));
+        assert_eq!(
+            assign_all(&mut restored, now),
+            vec![(robot(6001), OrderKey::new(1, 5))]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn a_draining_leader_hands_over_its_queue() {
+        // El lider (6001) y otro robot (6002) tienen un pedido cada uno y
+        // queda uno en cola
+        let (mut state, now) = state(&[(6001, 1), (6002, 1)], &[1, 2, 3]);
+        assign_all(&mut state, now);
+        let leader = LeaderOrderProcessing::with_state(state, 3, robot(0))
+            .unwrap()
+            .start();
+
+        // Lo que hace el lider al retirarse: se saca del reparto y entrega el
+        // estado
+        leader.do_send(DrainRobot {
+            addr: robot(6001),
+            handed_back: Vec::new(),
+        });
+        leader.do_send(RemoveRobot { addr: robot(6001) });
+        let snapshot = leader.send(HandOver).await.unwrap();
+        assert_eq!(snapshot.epoch, 3);
+        assert_eq!(
+            keys(&snapshot.orders),
+            vec![OrderKey::new(1, 1), OrderKey::new(1, 3)]
+        );
+        assert_eq!(snapshot.robots, vec![(robot(6002), 1)]);
+
+        // El sucesor reparte la cola entre los robots que quedan
+        let mut successor = LeaderState::from_snapshot(snapshot, now);
+        assert!(successor.next_assignment(now).is_none());
+        assert!(successor.finish_order(robot(6002), OrderKey::new(1, 2)));
+        assert_eq!(
+            assign_all(&mut successor, now),
+            vec![(robot(6002), OrderKey::new(1, 1))]
+        );
+        assert_eq!(
+            keys(&successor.snapshot().orders),
+            vec![OrderKey::new(1, 3)]
         );
     }
 }
@@ -0,0 +1,399 @@
+use crate::structures::order::Order;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+
+/// Cambio de estado de un pedido. Se guarda uno por linea, en JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum JournalEntry {
+    /// Se le pidio al gateway que autorice el pago.
+    Submitted { order: Order },
+    /// El gateway autorizo el pago; el pedido va al lider. `deadline` es el
+    /// plazo de entrega, en milisegundos desde UNIX_EPOCH.
+    Approved {
+        id: usize,
+        #[serde(default)]
+        deadline: Option<u64>,
+    },
+    /// El gateway no autorizo el pago.
+    Rejected { id: usize },
+    /// El lider confirmo que recibio el pedido.
+    Accepted { id: usize },
+    /// Llego el resultado del robot.
+    Finished { id: usize, completed: bool },
+    /// Paso el plazo sin resultado del robot; se anula el pago.
+    Expired { id: usize },
+    /// Se le mando al gateway que cobre o anule el pago. `owner` es la
+    /// interfaz duena si el pedido se adopto.
+    Settled {
+        id: usize,
+        #[serde(default)]
+        owner: Option<SocketAddr>,
+    },
+    /// Llego el resultado de un pedido de otra interfaz que no respondio.
+    Adopted {
+        owner: SocketAddr,
+        id: usize,
+        completed: bool,
+    },
+}
+
+/// Hasta donde llego un pedido segun el diario.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Authorizing,
+    Approved,
+    Accepted,
+    Rejected,
+    Finished { completed: bool },
+    Settled { completed: bool },
+    Expired { settled: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveredOrder {
+    pub order: Order,
+    pub stage: Stage,
+    /// Plazo de entrega anotado al aprobarse.
+    pub deadline: Option<u64>,
+}
+
+/// Pedido adoptado de otra interfaz.
+#[derive(Debug, Clone)]
+pub struct RecoveredAdoption {
+    pub owner: SocketAddr,
+    pub id: usize,
+    pub completed: bool,
+    pub settled: bool,
+}
+
+/// Lo que se retoma del diario.
+#[derive(Debug, Clone, Default)]
+pub struct Recovered {
+    pub orders: Vec<RecoveredOrder>,
+    pub adopted: Vec<RecoveredAdoption>,
+}
+
+/// Diario de los pedidos de una interfaz. Permite que una interfaz que se
+/// cayo retome sus pedidos donde quedaron.
+pub struct OrderJournal {
+    file: File,
+}
+
+impl OrderJournal {
+    /// Abre el diario para seguir escribiendo y devuelve el estado de cada
+    /// pedido anotado. Una ultima linea cortada (la interfaz se cayo mientras
+    /// escribia) se descarta.
+    pub fn open(path: &str) -> io::Result<(OrderJournal, Recovered)> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let entries = content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<JournalEntry>(line).ok());
+        let recovered = replay(entries);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok((OrderJournal { file }, recovered))
+    }
+
+    pub fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Aplica las entradas en orden y devuelve el ultimo estado de cada pedido,
+/// ordenados por id.
+pub fn replay<I>(entries: I) -> Recovered
+where
+    I: IntoIterator<Item = JournalEntry>,
+{
+    let mut orders: BTreeMap<usize, RecoveredOrder> = BTreeMap::new();
+    let mut adopted: BTreeMap<(SocketAddr, usize), RecoveredAdoption> = BTreeMap::new();
+    for entry in entries {
+        let (id, stage) = match entry {
+            JournalEntry::Submitted { order } => {
+                orders.insert(
+                    order.id,
+                    RecoveredOrder {
+                        order,
+                        stage: Stage::Authorizing,
+                        deadline: None,
+                    },
+                );
+                continue;
+            }
+            JournalEntry::Approved { id, deadline } => {
+                if let Some(order) = orders.get_mut(&id) {
+                    order.deadline = deadline;
+                }
+                (id, Stage::Approved)
+            }
+            JournalEntry::Rejected { id } => (id, Stage::Rejected),
+            JournalEntry::Accepted { id } => (id, Stage::Accepted),
+            JournalEntry::Finished { id, completed } => (id, Stage::Finished { completed }),
+            JournalEntry::Expired { id } => (id, Stage::Expired { settled: false }),
+            JournalEntry::Adopted {
+                owner,
+                id,
+                completed,
+            } => {
+                adopted.entry((owner, id)).or_insert(RecoveredAdoption {
+                    owner,
+                    id,
+                    completed,
+                    settled: false,
+                });
+                continue;
+            }
+            JournalEntry::Settled {
+                id,
+                owner: Some(owner),
+            } => {
+                if let Some(adoption) = adopted.get_mut(&(owner, id)) {
+                    adoption.settled = true;
+                }
+                continue;
+            }
+            JournalEntry::Settled { id, owner: None } => {
+                match orders.get(&id).map(|order| order.stage) {
+                    Some(Stage::Finished { completed }) => (id, Stage::Settled { completed }),
+                    Some(Stage::Expired { settled: false }) => {
+                        (id, Stage::Expired { settled: true })
+                    }
+                    _ => continue,
+                }
+            }
+        };
+        if let Some(order) = orders.get_mut(&id) {
+            // Un resultado no se pisa con un aviso atrasado del lider
+            let finished = matches!(
+                order.stage,
+                Stage::Finished { .. }
+                    | Stage::Settled { .. }
+                    | Stage::Rejected
+                    | Stage::Expired { .. }
+            );
+            if !finished
+                || matches!(
+                    stage,
+                    Stage::Settled { .. } | Stage::Expired { settled: true }
+                )
+            {
+                order.stage = stage;
+            }
+        }
+    }
+    Recovered {
+        orders: orders.into_values().collect(),
+        adopted: adopted.into_values().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: usize) -> Order {
+        Order::new(id, 7, vec!["Chocolate".to_string()], 850, 0.5, 1000)
+    }
+
+    fn owner(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Ruta de un diario temporal que no existe todavia.
+    fn journal_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("journal_{}_{}.jsonl", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn record_all(path: &str, entries: Vec<JournalEntry>) -> Recovered {
+        let (mut journal, recovered) = OrderJournal::open(path).unwrap();
+        for entry in entries {
+            journal.record(&entry).unwrap();
+        }
+        recovered
+    }
+
+    fn stages(recovered: &Recovered) -> Vec<(usize, Stage)> {
+        recovered
+            .orders
+            .iter()
+            .map(|recovered| (recovered.order.id, recovered.stage))
+            .collect()
+    }
+
+    #[test]
+    fn reopening_resumes_each_order_at_its_last_stage() {
+        let path = journal_path("stages");
+        let recovered = record_all(
+            &path,
+            vec![
+                JournalEntry::Submitted { order: order(1) },
+                JournalEntry::Submitted { order: order(2) },
+                JournalEntry::Submitted { order: order(3) },
+                JournalEntry::Approved {
+                    id: 1,
+                    deadline: Some(1_000),
+                },
+                JournalEntry::Accepted { id: 1 },
+                JournalEntry::Finished {
+                    id: 1,
+                    completed: true,
+                },
+                JournalEntry::Settled { id: 1, owner: None },
+                JournalEntry::Approved {
+                    id: 2,
+                    deadline: Some(2_000),
+                },
+                JournalEntry::Finished {
+                    id: 2,
+                    completed: false,
+                },
+            ],
+        );
+        assert!(recovered.orders.is_empty());
+
+        let (_, recovered) = OrderJournal::open(&path).unwrap();
+        assert_eq!(
+            stages(&recovered),
+            vec![
+                (1, Stage::Settled { completed: true }),
+                (2, Stage::Finished { completed: false }),
+                (3, Stage::Authorizing),
+            ]
+        );
+        let deadlines: Vec<Option<u64>> = recovered.orders.iter().map(|o| o.deadline).collect();
+        assert_eq!(deadlines, vec![Some(1_000), Some(2_000), None]);
+        assert_eq!(recovered.orders[0].order.products, vec!["Chocolate"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_late_leader_ack_does_not_undo_a_result() {
+        let path = journal_path("late_ack");
+        record_all(
+            &path,
+            vec![
+                JournalEntry::Submitted { order: order(1) },
+                JournalEntry::Approved {
+                    id: 1,
+                    deadline: None,
+                },
+                JournalEntry::Finished {
+                    id: 1,
+                    completed: true,
+                },
+                JournalEntry::Accepted { id: 1 },
+                JournalEntry::Submitted { order: order(2) },
+                JournalEntry::Approved {
+                    id: 2,
+                    deadline: None,
+                },
+                JournalEntry::Expired { id: 2 },
+                JournalEntry::Finished {
+                    id: 2,
+                    completed: true,
+                },
+                JournalEntry::Settled { id: 2, owner: None },
+            ],
+        );
+        let (_, recovered) = OrderJournal::open(&path).unwrap();
+        assert_eq!(
+            stages(&recovered),
+            vec![
+                (1, Stage::Finished { completed: true }),
+                (2, Stage::Expired { settled: true }),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_truncated_last_line_is_dropped_and_writing_continues() {
+        let path = journal_path("truncated");
+        record_all(
+            &path,
+            vec![
+                JournalEntry::Submitted { order: order(1) },
+                JournalEntry::Approved {
+                    id: 1,
+                    deadline: None,
+                },
+            ],
+        );
+        // La interfaz se cayo en medio de la linea
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"event\":\"Finished\",\"id\":1,\"compl")
+            .unwrap();
+        drop(file);
+
+        let recovered = record_all(&path, vec![JournalEntry::Accepted { id: 1 }]);
+        assert_eq!(stages(&recovered), vec![(1, Stage::Approved)]);
+        // Lo que se escribe despues queda en su propia linea
+        let (_, recovered) = OrderJournal::open(&path).unwrap();
+        assert_eq!(stages(&recovered), vec![(1, Stage::Accepted)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn adopted_orders_are_kept_per_owner() {
+        let path = journal_path("adopted");
+        record_all(
+            &path,
+            vec![
+                JournalEntry::Submitted { order: order(5) },
+                JournalEntry::Adopted {
+                    owner: owner(9003),
+                    id: 5,
+                    completed: true,
+                },
+                JournalEntry::Adopted {
+                    owner: owner(9004),
+                    id: 5,
+                    completed: false,
+                },
+                // Repetido: vale el primero
+                JournalEntry::Adopted {
+                    owner: owner(9003),
+                    id: 5,
+                    completed: false,
+                },
+                JournalEntry::Settled {
+                    id: 5,
+                    owner: Some(owner(9003)),
+                },
+                JournalEntry::Settled {
+                    id: 6,
+                    owner: Some(owner(9004)),
+                },
+            ],
+        );
+        let (_, recovered) = OrderJournal::open(&path).unwrap();
+        let adopted: Vec<(SocketAddr, usize, bool, bool)> = recovered
+            .adopted
+            .iter()
+            .map(|a| (a.owner, a.id, a.completed, a.settled))
+            .collect();
+        assert_eq!(
+            adopted,
+            vec![(owner(9003), 5, true, true), (owner(9004), 5, false, false),]
+        );
+        // Cobrar el adoptado no toca el pedido propio con el mismo id
+        assert_eq!(stages(&recovered), vec![(5, Stage::Authorizing)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::common::read_file::InvalidRecord;
+use crate::defines::status_payment::StatusPayment;
+use serde::Serialize;
+use std::net::SocketAddr;
+
+/// Estado final de un pedido visto desde la interfaz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OrderOutcome {
+    /// Todavia no termino: espera al gateway o al robot.
+    Pending,
+    /// El gateway no autorizo el pago.
+    Rejected,
+    Completed,
+    /// El robot no pudo prepararlo (por ejemplo, falta de stock).
+    Incomplete,
+    /// No llego el resultado del robot dentro del plazo; se anulo el pago.
+    Expired,
+}
+
+impl OrderOutcome {
+    /// Estado segun el resultado que manda el robot.
+    pub fn from_result(completed: bool) -> OrderOutcome {
+        if completed {
+            OrderOutcome::Completed
+        } else {
+            OrderOutcome::Incomplete
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrderResult {
+    pub id: usize,
+    pub outcome: OrderOutcome,
+    /// Paid si se cobro, Unpaid si se anulo o nunca se autorizo.
+    pub payment: StatusPayment,
+}
+
+impl OrderResult {
+    pub fn new(id: usize) -> OrderResult {
+        OrderResult {
+            id,
+            outcome: OrderOutcome::Pending,
+            payment: StatusPayment::Pending,
+        }
+    }
+
+    /// Un pedido termina cuando tiene resultado y el pago ya se cobro o anulo.
+    pub fn is_finished(&self) -> bool {
+        self.outcome != OrderOutcome::Pending && self.payment != StatusPayment::Pending
+    }
+}
+
+/// Pedido de otra interfaz que se adopto porque la suya no respondio.
+#[derive(Debug, Clone, Serialize)]
+pub struct AdoptedOrder {
+    pub owner: SocketAddr,
+    #[serde(flatten)]
+    pub result: OrderResult,
+}
+
+/// Resultado de todos los pedidos de una interfaz, tal como se escribe en el
+/// archivo de resultados.
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceReport {
+    pub interface: usize,
+    pub completed: usize,
+    pub incomplete: usize,
+    pub rejected: usize,
+    pub expired: usize,
+    pub pending: usize,
+    pub orders: Vec<OrderResult>,
+    /// Registros del archivo que no se mandaron por ser invalidos.
+    pub invalid: Vec<InvalidRecord>,
+    pub adopted: Vec<AdoptedOrder>,
+}
+
+impl InterfaceReport {
+    pub fn new(
+        interface: usize,
+        orders: Vec<OrderResult>,
+        invalid: Vec<InvalidRecord>,
+        adopted: Vec<AdoptedOrder>,
+    ) -> InterfaceReport {
+        let count = |outcome| {
+            orders
+                .iter()
+                .filter(|order| order.outcome == outcome)
+                .count()
+        };
+        InterfaceReport {
+            interface,
+            completed: count(OrderOutcome::Completed),
+            incomplete: count(OrderOutcome::Incomplete),
+            rejected: count(OrderOutcome::Rejected),
+            expired: count(OrderOutcome::Expired),
+            pending: orders.iter().filter(|order| !order.is_finished()).count()
+                + adopted
+                    .iter()
+                    .filter(|order| !order.result.is_finished())
+                    .count(),
+            orders,
+            invalid,
+            adopted,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pending == 0
+    }
+
+    /// Pedidos que terminaron sin completarse, contando los invalidos.
+    pub fn failures(&self) -> usize {
+        self.incomplete + self.rejected + self.expired + self.invalid.len()
+    }
+}
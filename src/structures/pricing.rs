@@ -0,0 +1,295 @@
+use crate::common::order_entry::SIZES;
+use crate::defines::flavours::find_flavour;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Los porcentajes se aplican en centesimos para no usar floats
+const PERCENT: u64 = 100;
+
+/// Precio de un tamaño, en pesos.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizePrice {
+    pub size: f32,
+    pub price: usize,
+}
+
+/// Recargo fijo por incluir un sabor premium.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Surcharge {
+    pub flavour: String,
+    pub amount: usize,
+}
+
+/// Descuento porcentual para los pedidos hechos entre `from` (incluida) y
+/// `to` (excluida). Si `from` es mayor que `to` la franja cruza la
+/// medianoche.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Promotion {
+    pub from: u32,
+    pub to: u32,
+    pub discount: u32,
+}
+
+impl Promotion {
+    fn applies(&self, hour: u32) -> bool {
+        if self.from <= self.to {
+            (self.from..self.to).contains(&hour)
+        } else {
+            hour >= self.from || hour < self.to
+        }
+    }
+}
+
+/// Lista de precios de la heladeria. Se lee de un JSON; los campos que faltan
+/// toman el valor por defecto (sin recargos, promociones ni impuestos).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricingConfig {
+    pub sizes: Vec<SizePrice>,
+    #[serde(default)]
+    pub premium: Vec<Surcharge>,
+    #[serde(default)]
+    pub promotions: Vec<Promotion>,
+    /// Impuesto en porcentaje sobre el total con descuento.
+    #[serde(default)]
+    pub tax: u32,
+    /// Diferencia en horas con UTC para saber la hora del local.
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        PricingConfig {
+            sizes: vec![
+                SizePrice {
+                    size: 0.25,
+                    price: 500,
+                },
+                SizePrice {
+                    size: 0.5,
+                    price: 850,
+                },
+                SizePrice {
+                    size: 1.0,
+                    price: 1500,
+                },
+            ],
+            premium: Vec::new(),
+            promotions: Vec::new(),
+            tax: 0,
+            utc_offset: 0,
+        }
+    }
+}
+
+impl PricingConfig {
+    pub fn from_file(path: &str) -> io::Result<PricingConfig> {
+        let content = fs::read_to_string(path)?;
+        let config: PricingConfig = serde_json::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+
+    /// Revisa que se pueda cobrar cualquier pedido valido.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(size) = SIZES.iter().find(|size| self.size_price(**size).is_none()) {
+            return Err(format!("missing price for size {}", size));
+        }
+        if let Some(surcharge) = self
+            .premium
+            .iter()
+            .find(|surcharge| find_flavour(&surcharge.flavour).is_none())
+        {
+            return Err(format!("unknown premium flavour {}", surcharge.flavour));
+        }
+        if let Some(promotion) = self
+            .promotions
+            .iter()
+            .find(|promotion| promotion.from > 23 || promotion.to > 24 || promotion.discount > 100)
+        {
+            return Err(format!("invalid promotion {:?}", promotion));
+        }
+        Ok(())
+    }
+
+    fn size_price(&self, size: f32) -> Option<usize> {
+        self.sizes
+            .iter()
+            .find(|entry| (entry.size - size).abs() < f32::EPSILON)
+            .map(|entry| entry.price)
+    }
+}
+
+/// Calcula el total de los pedidos. Todas las interfaces cobran a traves de
+/// este modulo.
+#[derive(Debug, Clone, Default)]
+pub struct Pricing {
+    config: PricingConfig,
+}
+
+impl Pricing {
+    pub fn new(config: PricingConfig) -> Pricing {
+        Pricing { config }
+    }
+
+    /// Hora actual del local, de 0 a 23.
+    pub fn current_hour(&self) -> u32 {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let hour = secs / 3600 + self.config.utc_offset as i64;
+        hour.rem_euclid(24) as u32
+    }
+
+    /// Total de un pedido hecho a la hora `hour`: precio del tamaño mas los
+    /// recargos de sabores premium, menos la promocion de esa hora (la
+    /// primera que aplique), mas el impuesto. Se redondea al peso.
+    pub fn total<S: AsRef<str>>(
+        &self,
+        size: f32,
+        flavours: &[S],
+        hour: u32,
+    ) -> Result<usize, String> {
+        let base = self
+            .config
+            .size_price(size)
+            .ok_or(format!("no price for size {}", size))?;
+        let surcharges: usize = flavours
+            .iter()
+            .filter_map(|flavour| {
+                self.config.premium.iter().find(|surcharge| {
+                    surcharge
+                        .flavour
+                        .eq_ignore_ascii_case(flavour.as_ref().trim())
+                })
+            })
+            .map(|surcharge| surcharge.amount)
+            .sum();
+        let discount = self
+            .config
+            .promotions
+            .iter()
+            .find(|promotion| promotion.applies(hour))
+            .map_or(0, |promotion| promotion.discount as u64);
+        let subtotal = (base + surcharges) as u64;
+        let scaled = subtotal * (PERCENT - discount) * (PERCENT + self.config.tax as u64);
+        let scale = PERCENT * PERCENT;
+        Ok(((scaled + scale / 2) / scale) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PricingConfig {
+        PricingConfig {
+            premium: vec![Surcharge {
+                flavour: "Dulce de Leche".to_string(),
+                amount: 150,
+            }],
+            promotions: vec![
+                Promotion {
+                    from: 15,
+                    to: 18,
+                    discount: 20,
+                },
+                Promotion {
+                    from: 22,
+                    to: 2,
+                    discount: 50,
+                },
+            ],
+            tax: 21,
+            ..PricingConfig::default()
+        }
+    }
+
+    #[test]
+    fn default_prices_match_sizes() {
+        let pricing = Pricing::default();
+        assert_eq!(pricing.total(0.25, &["Chocolate"], 12), Ok(500));
+        assert_eq!(pricing.total(0.5, &["Chocolate"], 12), Ok(850));
+        assert_eq!(pricing.total(1.0, &["Chocolate"], 12), Ok(1500));
+    }
+
+    #[test]
+    fn unknown_size_has_no_price() {
+        let pricing = Pricing::default();
+        assert!(pricing.total(0.75, &["Chocolate"], 12).is_err());
+    }
+
+    #[test]
+    fn premium_flavours_add_surcharge() {
+        let pricing = Pricing::new(PricingConfig {
+            tax: 0,
+            promotions: Vec::new(),
+            ..config()
+        });
+        assert_eq!(
+            pricing.total(0.5, &["dulce de leche", "Chocolate"], 12),
+            Ok(1000)
+        );
+        assert_eq!(pricing.total(0.5, &["Vainilla"], 12), Ok(850));
+    }
+
+    #[test]
+    fn tax_applies_over_total() {
+        let pricing = Pricing::new(config());
+        // (850 + 150) * 1.21
+        assert_eq!(pricing.total(0.5, &["Dulce de Leche"], 12), Ok(1210));
+        // 500 * 1.21 = 605
+        assert_eq!(pricing.total(0.25, &["Frutilla"], 12), Ok(605));
+    }
+
+    #[test]
+    fn promotion_applies_only_inside_its_hours() {
+        let pricing = Pricing::new(config());
+        // 1500 * 0.8 * 1.21
+        assert_eq!(pricing.total(1.0, &["Chocolate"], 15), Ok(1452));
+        assert_eq!(pricing.total(1.0, &["Chocolate"], 17), Ok(1452));
+        assert_eq!(pricing.total(1.0, &["Chocolate"], 18), Ok(1815));
+        assert_eq!(pricing.total(1.0, &["Chocolate"], 14), Ok(1815));
+    }
+
+    #[test]
+    fn promotion_can_cross_midnight() {
+        let pricing = Pricing::new(config());
+        // 500 * 0.5 * 1.21 = 302.5, redondea a 303
+        assert_eq!(pricing.total(0.25, &["Chocolate"], 23), Ok(303));
+        assert_eq!(pricing.total(0.25, &["Chocolate"], 1), Ok(303));
+        assert_eq!(pricing.total(0.25, &["Chocolate"], 2), Ok(605));
+    }
+
+    #[test]
+    fn config_must_price_every_size() {
+        let mut config = config();
+        assert!(config.validate().is_ok());
+        config.sizes.retain(|entry| entry.size != 0.5);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn config_rejects_unknown_premium_flavour() {
+        let mut config = config();
+        config.premium.push(Surcharge {
+            flavour: "Menta".to_string(),
+            amount: 100,
+        });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn missing_fields_take_defaults() {
+        let config: PricingConfig =
+            serde_json::from_str(r#"{"sizes": [{"size": 0.25, "price": 600}, {"size": 0.5, "price": 900}, {"size": 1.0, "price": 1600}]}"#)
+                .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(Pricing::new(config).total(0.5, &["Chocolate"], 20), Ok(900));
+    }
+}
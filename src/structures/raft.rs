@@ -0,0 +1,930 @@
+use crate::structures::leader_order_processing::Command;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// Ticks sin noticias del lider antes de postularse (se sortea en el rango)
+const ELECTION_TICKS_MIN: u32 = 10;
+const ELECTION_TICKS_MAX: u32 = 20;
+// Cada cuantos ticks el lider manda AppendEntries aunque no haya novedades
+const HEARTBEAT_TICKS: u32 = 2;
+// Entradas por AppendEntries, para no pasarse del tamaño de un datagrama
+const MAX_ENTRIES_PER_MESSAGE: usize = 32;
+
+/// Entrada del log replicado. Sin comando es la entrada vacia con la que un
+/// lider nuevo confirma lo que quedo de mandatos anteriores.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub command: Option<Command>,
+}
+
+/// Mensajes de Raft entre robots. Viajan como `Raft:{json}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        last_index: usize,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_index: usize,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        commit: usize,
+    },
+    AppendReply {
+        term: u64,
+        success: bool,
+        match_index: usize,
+    },
+}
+
+/// Cambio del estado que un nodo tiene que guardar en disco antes de
+/// contestar. Se guarda uno por linea, en JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "record")]
+pub enum RaftRecord {
+    /// Mandato actual y a quien se voto en el.
+    Vote {
+        term: u64,
+        voted_for: Option<SocketAddr>,
+    },
+    /// Entradas del log desde la posicion `from` (la primera es 1). Lo que
+    /// habia desde esa posicion se descarta.
+    Entries { from: usize, entries: Vec<LogEntry> },
+}
+
+/// Lo que el nodo le pide al robot que haga.
+#[derive(Debug)]
+pub enum RaftAction {
+    /// Guardar estos cambios antes de seguir con las demas acciones. Si no se
+    /// pueden guardar no hay que mandar nada.
+    Persist(Vec<RaftRecord>),
+    /// Mandar el mensaje a ese miembro del grupo.
+    Send(SocketAddr, RaftMessage),
+    /// Aplicar en orden comandos ya confirmados por la mayoria.
+    Apply(Vec<Command>),
+    /// Este nodo gano el mandato indicado.
+    BecameLeader(u64),
+    /// Este nodo era lider y aparecio un mandato mas nuevo.
+    SteppedDown(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Nodo de Raft de un robot. Como `ElectionState`, no hace I/O: recibe
+/// mensajes y ticks y devuelve las acciones a ejecutar.
+///
+/// El grupo es fijo. El mandato, el voto y el log se devuelven para guardar
+/// con `RaftAction::Persist` antes de cualquier respuesta, y un robot que se
+/// reinicia los recupera con `RaftNode::restore`. Lo confirmado no se
+/// guarda: se vuelve a aplicar cuando el lider avisa hasta donde confirmo.
+#[derive(Clone)]
+pub struct RaftNode {
+    me: SocketAddr,
+    peers: Vec<SocketAddr>,
+    role: Role,
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    log: Vec<LogEntry>,
+    commit: usize,
+    applied: usize,
+    votes: HashSet<SocketAddr>,
+    next_index: HashMap<SocketAddr, usize>,
+    match_index: HashMap<SocketAddr, usize>,
+    ticks: u32,
+    timeout: u32,
+    // Cambios que todavia no se devolvieron para guardar: el voto y la
+    // primera posicion del log que cambio
+    unsaved_vote: bool,
+    unsaved_from: Option<usize>,
+}
+
+fn election_timeout() -> u32 {
+    rand::thread_rng().gen_range(ELECTION_TICKS_MIN, ELECTION_TICKS_MAX)
+}
+
+impl RaftNode {
+    pub fn new(me: SocketAddr, peers: Vec<SocketAddr>) -> RaftNode {
+        RaftNode {
+            me,
+            peers: peers.into_iter().filter(|peer| *peer != me).collect(),
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit: 0,
+            applied: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            ticks: 0,
+            timeout: election_timeout(),
+            unsaved_vote: false,
+            unsaved_from: None,
+        }
+    }
+
+    /// Nodo que retoma lo que guardo antes de reiniciarse.
+    pub fn restore(me: SocketAddr, peers: Vec<SocketAddr>, records: Vec<RaftRecord>) -> RaftNode {
+        let mut node = RaftNode::new(me, peers);
+        for record in records {
+            match record {
+                RaftRecord::Vote { term, voted_for } => {
+                    node.term = term;
+                    node.voted_for = voted_for;
+                }
+                // Un hueco solo puede venir de un archivo roto: se ignora
+                RaftRecord::Entries { from, entries }
+                    if from >= 1 && from <= node.log.len() + 1 =>
+                {
+                    node.log.truncate(from - 1);
+                    node.log.extend(entries);
+                }
+                RaftRecord::Entries { .. } => {}
+            }
+        }
+        node
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    fn majority(&self) -> usize {
+        let group = self.peers.len() + 1;
+        group / 2 + 1
+    }
+
+    fn last_index(&self) -> usize {
+        self.log.len()
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            i => self.log.get(i - 1).map_or(0, |entry| entry.term),
+        }
+    }
+
+    /// Avanza el reloj: el lider manda latidos y los demas se postulan si
+    /// dejaron de escuchar al lider.
+    pub fn tick(&mut self) -> Vec<RaftAction> {
+        self.ticks += 1;
+        let actions = match self.role {
+            Role::Leader if self.ticks >= HEARTBEAT_TICKS => {
+                self.ticks = 0;
+                self.broadcast_append()
+            }
+            Role::Leader => Vec::new(),
+            _ if self.ticks >= self.timeout => self.start_election(),
+            _ => Vec::new(),
+        };
+        self.persist(actions)
+    }
+
+    /// Agrega un comando al log si este nodo es el lider. Devuelve None si no
+    /// lo es, para que quien lo propuso no lo de por aceptado.
+    pub fn propose(&mut self, command: Command) -> Option<Vec<RaftAction>> {
+        if !self.is_leader() {
+            return None;
+        }
+        self.push_entry(Some(command));
+        let mut actions = self.broadcast_append();
+        actions.extend(self.advance_commit());
+        Some(self.persist(actions))
+    }
+
+    pub fn on_message(&mut self, from: SocketAddr, message: RaftMessage) -> Vec<RaftAction> {
+        let actions = self.handle_message(from, message);
+        self.persist(actions)
+    }
+
+    /// Antepone a las acciones los cambios que hay que guardar antes de
+    /// ejecutarlas.
+    fn persist(&mut self, actions: Vec<RaftAction>) -> Vec<RaftAction> {
+        let mut records = Vec::new();
+        if std::mem::take(&mut self.unsaved_vote) {
+            records.push(RaftRecord::Vote {
+                term: self.term,
+                voted_for: self.voted_for,
+            });
+        }
+        if let Some(from) = self.unsaved_from.take() {
+            records.push(RaftRecord::Entries {
+                from,
+                entries: self.log[(from - 1).min(self.log.len())..].to_vec(),
+            });
+        }
+        if records.is_empty() {
+            return actions;
+        }
+        let mut persisted = vec![RaftAction::Persist(records)];
+        persisted.extend(actions);
+        persisted
+    }
+
+    fn set_vote(&mut self, term: u64, voted_for: Option<SocketAddr>) {
+        self.term = term;
+        self.voted_for = voted_for;
+        self.unsaved_vote = true;
+    }
+
+    /// Marca el log como cambiado desde la posicion `index`.
+    fn log_changed(&mut self, index: usize) {
+        self.unsaved_from = Some(self.unsaved_from.map_or(index, |from| from.min(index)));
+    }
+
+    fn push_entry(&mut self, command: Option<Command>) {
+        self.log.push(LogEntry {
+            term: self.term,
+            command,
+        });
+        self.log_changed(self.last_index());
+    }
+
+    fn handle_message(&mut self, from: SocketAddr, message: RaftMessage) -> Vec<RaftAction> {
+        let mut actions = Vec::new();
+        let term = match &message {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::Vote { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::AppendReply { term, .. } => *term,
+        };
+        if term > self.term {
+            actions.extend(self.become_follower(term));
+        }
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                last_index,
+                last_term,
+            } => {
+                // Solo se vota a quien tiene un log al menos tan completo
+                let up_to_date =
+                    (last_term, last_index) >= (self.term_at(self.last_index()), self.last_index());
+                let granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.is_none_or(|voted| voted == from);
+                if granted {
+                    self.set_vote(self.term, Some(from));
+                    self.ticks = 0;
+                }
+                actions.push(RaftAction::Send(
+                    from,
+                    RaftMessage::Vote {
+                        term: self.term,
+                        granted,
+                    },
+                ));
+            }
+            RaftMessage::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.majority() {
+                        actions.extend(self.become_leader());
+                    }
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => actions
+                .extend(self.handle_append(from, term, prev_index, prev_term, entries, commit)),
+            RaftMessage::AppendReply {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return actions;
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_insert(0);
+                    *matched = (*matched).max(match_index);
+                    self.next_index.insert(from, *matched + 1);
+                    actions.extend(self.advance_commit());
+                } else {
+                    // Se retrocede hasta encontrar la ultima entrada en comun
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    self.next_index
+                        .insert(from, (match_index + 1).min(next.saturating_sub(1)).max(1));
+                    actions.push(self.append_to(from));
+                }
+            }
+        }
+        actions
+    }
+
+    fn handle_append(
+        &mut self,
+        from: SocketAddr,
+        term: u64,
+        prev_index: usize,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        commit: usize,
+    ) -> Vec<RaftAction> {
+        let reply = |term, success, match_index| {
+            RaftAction::Send(
+                from,
+                RaftMessage::AppendReply {
+                    term,
+                    success,
+                    match_index,
+                },
+            )
+        };
+        if term < self.term {
+            return vec![reply(self.term, false, 0)];
+        }
+        // Hay un lider en este mandato: un candidato desiste
+        self.role = Role::Follower;
+        self.ticks = 0;
+        if prev_index > self.last_index() || self.term_at(prev_index) != prev_term {
+            let hint = self.last_index().min(prev_index.saturating_sub(1));
+            return vec![reply(self.term, false, hint)];
+        }
+        let last_new = prev_index + entries.len();
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + offset + 1;
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                // Lo que no coincide con el lider se descarta
+                self.log.truncate(index - 1);
+            }
+            self.log.push(entry);
+            self.log_changed(index);
+        }
+        let mut actions = Vec::new();
+        if commit > self.commit {
+            self.commit = commit.min(last_new);
+            actions.extend(self.apply_committed());
+        }
+        actions.push(reply(self.term, true, last_new));
+        actions
+    }
+
+    fn become_follower(&mut self, term: u64) -> Vec<RaftAction> {
+        let was_leader = self.role == Role::Leader;
+        self.set_vote(term, None);
+        self.role = Role::Follower;
+        self.ticks = 0;
+        if was_leader {
+            vec![RaftAction::SteppedDown(term)]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn start_election(&mut self) -> Vec<RaftAction> {
+        self.set_vote(self.term + 1, Some(self.me));
+        self.role = Role::Candidate;
+        self.votes = [self.me].iter().copied().collect();
+        self.ticks = 0;
+        self.timeout = election_timeout();
+        if self.votes.len() >= self.majority() {
+            return self.become_leader();
+        }
+        let message = RaftMessage::RequestVote {
+            term: self.term,
+            last_index: self.last_index(),
+            last_term: self.term_at(self.last_index()),
+        };
+        self.peers
+            .iter()
+            .map(|peer| RaftAction::Send(*peer, message.clone()))
+            .collect()
+    }
+
+    fn become_leader(&mut self) -> Vec<RaftAction> {
+        self.role = Role::Leader;
+        self.ticks = 0;
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (*peer, next)).collect();
+        self.match_index = self.peers.iter().map(|peer| (*peer, 0)).collect();
+        // Solo se cuentan replicas de entradas del mandato actual, asi que se
+        // agrega una vacia para confirmar las de mandatos anteriores
+        self.push_entry(None);
+        let mut actions = vec![RaftAction::BecameLeader(self.term)];
+        actions.extend(self.broadcast_append());
+        actions.extend(self.advance_commit());
+        actions
+    }
+
+    fn append_to(&self, peer: SocketAddr) -> RaftAction {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1).max(1);
+        let prev_index = (next - 1).min(self.last_index());
+        let entries = self.log[prev_index..]
+            .iter()
+            .take(MAX_ENTRIES_PER_MESSAGE)
+            .cloned()
+            .collect();
+        RaftAction::Send(
+            peer,
+            RaftMessage::AppendEntries {
+                term: self.term,
+                prev_index,
+                prev_term: self.term_at(prev_index),
+                entries,
+                commit: self.commit,
+            },
+        )
+    }
+
+    fn broadcast_append(&self) -> Vec<RaftAction> {
+        self.peers
+            .iter()
+            .map(|peer| self.append_to(*peer))
+            .collect()
+    }
+
+    /// Confirma la entrada mas alta del mandato actual que ya tiene la
+    /// mayoria y aplica lo que haya quedado confirmado.
+    fn advance_commit(&mut self) -> Vec<RaftAction> {
+        for index in (self.commit + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if replicas >= self.majority() {
+                self.commit = index;
+                return self.apply_committed();
+            }
+        }
+        Vec::new()
+    }
+
+    fn apply_committed(&mut self) -> Vec<RaftAction> {
+        if self.applied >= self.commit {
+            return Vec::new();
+        }
+        let commands: Vec<Command> = self.log[self.applied..self.commit]
+            .iter()
+            .filter_map(|entry| entry.command.clone())
+            .collect();
+        self.applied = self.commit;
+        if commands.is_empty() {
+            Vec::new()
+        } else {
+            vec![RaftAction::Apply(commands)]
+        }
+    }
+}
+
+/// Archivo donde un robot guarda su estado de Raft, como el diario de una
+/// interfaz.
+#[derive(Clone)]
+pub struct RaftStorage {
+    file: Arc<File>,
+}
+
+impl RaftStorage {
+    /// Abre el archivo para seguir escribiendo y devuelve lo que ya tenia,
+    /// para `RaftNode::restore`. Una ultima linea cortada se descarta.
+    pub fn open(path: &str) -> io::Result<(RaftStorage, Vec<RaftRecord>)> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let records = content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<RaftRecord>(line).ok())
+            .collect();
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok((
+            RaftStorage {
+                file: Arc::new(file),
+            },
+            records,
+        ))
+    }
+
+    /// Escribe los cambios y espera a que lleguen al disco.
+    pub fn save(&self, records: &[RaftRecord]) -> io::Result<()> {
+        let mut lines = String::new();
+        for record in records {
+            lines.push_str(&serde_json::to_string(record)?);
+            lines.push('\n');
+        }
+        let mut file: &File = &self.file;
+        file.write_all(lines.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, VecDeque};
+
+    fn node_addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Comando reconocible para seguir una entrada por el log.
+    fn command(port: u16) -> Command {
+        Command::RemoveRobot {
+            addr: node_addr(port),
+        }
+    }
+
+    fn entry(term: u64, port: u16) -> LogEntry {
+        LogEntry {
+            term,
+            command: Some(command(port)),
+        }
+    }
+
+    /// Puertos de los comandos del log, para compararlo de un vistazo.
+    fn log_of(node: &RaftNode) -> Vec<(u64, Option<u16>)> {
+        node.log
+            .iter()
+            .map(|entry| (entry.term, entry.command.as_ref().map(port_of)))
+            .collect()
+    }
+
+    fn port_of(command: &Command) -> u16 {
+        match command {
+            Command::RemoveRobot { addr } => addr.port(),
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    fn applied(actions: &[RaftAction]) -> Vec<u16> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                RaftAction::Apply(commands) => Some(commands.iter().map(port_of)),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    fn sent(actions: &[RaftAction]) -> Vec<(SocketAddr, RaftMessage)> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                RaftAction::Send(to, message) => Some((*to, message.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn vote_granted(actions: &[RaftAction]) -> Option<bool> {
+        sent(actions)
+            .into_iter()
+            .find_map(|(_, message)| match message {
+                RaftMessage::Vote { granted, .. } => Some(granted),
+                _ => None,
+            })
+    }
+
+    fn append_reply(actions: &[RaftAction]) -> Option<(bool, usize)> {
+        sent(actions)
+            .into_iter()
+            .find_map(|(_, message)| match message {
+                RaftMessage::AppendReply {
+                    success,
+                    match_index,
+                    ..
+                } => Some((success, match_index)),
+                _ => None,
+            })
+    }
+
+    /// Grupo simulado: los mensajes se entregan en orden y los nodos caidos
+    /// no reciben ni mandan nada. Se guarda lo aplicado por cada nodo.
+    struct Group {
+        nodes: BTreeMap<SocketAddr, RaftNode>,
+        crashed: Vec<SocketAddr>,
+        applied: BTreeMap<SocketAddr, Vec<u16>>,
+    }
+
+    impl Group {
+        fn new(ports: &[u16]) -> Group {
+            let addrs: Vec<SocketAddr> = ports.iter().map(|port| node_addr(*port)).collect();
+            Group {
+                nodes: addrs
+                    .iter()
+                    .map(|addr| (*addr, RaftNode::new(*addr, addrs.clone())))
+                    .collect(),
+                crashed: Vec::new(),
+                applied: BTreeMap::new(),
+            }
+        }
+
+        fn node(&self, port: u16) -> &RaftNode {
+            &self.nodes[&node_addr(port)]
+        }
+
+        /// Hace vencer el plazo de un nodo y entrega todo lo que resulte.
+        fn campaign(&mut self, port: u16) {
+            let addr = node_addr(port);
+            let node = self.nodes.get_mut(&addr).unwrap();
+            node.ticks = node.timeout - 1;
+            let actions = node.tick();
+            self.deliver(addr, actions);
+        }
+
+        fn propose(&mut self, port: u16, proposed: u16) -> bool {
+            let addr = node_addr(port);
+            match self
+                .nodes
+                .get_mut(&addr)
+                .unwrap()
+                .propose(command(proposed))
+            {
+                Some(actions) => {
+                    self.deliver(addr, actions);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        /// Un latido del lider, para que los demas se enteren de lo confirmado.
+        fn heartbeat(&mut self, port: u16) {
+            let addr = node_addr(port);
+            let actions = self.nodes.get_mut(&addr).unwrap().broadcast_append();
+            self.deliver(addr, actions);
+        }
+
+        fn deliver(&mut self, from: SocketAddr, actions: Vec<RaftAction>) {
+            let mut queue: VecDeque<(SocketAddr, Vec<RaftAction>)> = VecDeque::new();
+            queue.push_back((from, actions));
+            while let Some((from, actions)) = queue.pop_front() {
+                self.applied
+                    .entry(from)
+                    .or_default()
+                    .extend(applied(&actions));
+                for (to, message) in sent(&actions) {
+                    if self.crashed.contains(&to) {
+                        continue;
+                    }
+                    let node = self.nodes.get_mut(&to).unwrap();
+                    queue.push_back((to, node.on_message(from, message)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_candidate_with_a_majority_becomes_leader() {
+        let mut group = Group::new(&[6001, 6002, 6003]);
+        group.crashed.push(node_addr(6003));
+        group.campaign(6001);
+
+        assert!(group.node(6001).is_leader());
+        assert_eq!(group.node(6001).term(), 1);
+        assert!(!group.node(6002).is_leader());
+        assert_eq!(group.node(6002).voted_for, Some(node_addr(6001)));
+        // La entrada vacia del mandato ya esta en la mayoria
+        assert_eq!(log_of(group.node(6002)), vec![(1, None)]);
+        assert_eq!(group.node(6001).commit, 1);
+    }
+
+    #[test]
+    fn a_candidate_without_a_majority_does_not_lead() {
+        let mut group = Group::new(&[6001, 6002, 6003]);
+        group.crashed.extend([node_addr(6002), node_addr(6003)]);
+        group.campaign(6001);
+
+        assert!(!group.node(6001).is_leader());
+        assert_eq!(group.node(6001).role, Role::Candidate);
+        assert!(!group.propose(6001, 9001));
+    }
+
+    #[test]
+    fn votes_once_per_term_and_only_for_complete_logs() {
+        let mut node = RaftNode::new(node_addr(6001), vec![node_addr(6002), node_addr(6003)]);
+        let request = |term, last_index, last_term| RaftMessage::RequestVote {
+            term,
+            last_index,
+            last_term,
+        };
+
+        let actions = node.on_message(node_addr(6002), request(1, 0, 0));
+        assert_eq!(vote_granted(&actions), Some(true));
+        // Repetido al mismo candidato se vuelve a conceder, a otro no
+        let actions = node.on_message(node_addr(6002), request(1, 0, 0));
+        assert_eq!(vote_granted(&actions), Some(true));
+        let actions = node.on_message(node_addr(6003), request(1, 0, 0));
+        assert_eq!(vote_granted(&actions), Some(false));
+        // Un mandato viejo no recibe voto
+        let actions = node.on_message(node_addr(6003), request(0, 5, 0));
+        assert_eq!(vote_granted(&actions), Some(false));
+
+        node.on_message(
+            node_addr(6002),
+            RaftMessage::AppendEntries {
+                term: 1,
+                prev_index: 0,
+                prev_term: 0,
+                entries: vec![entry(1, 9001), entry(1, 9002)],
+                commit: 0,
+            },
+        );
+        // Mandato nuevo pero con log mas corto o de un mandato anterior
+        let actions = node.on_message(node_addr(6003), request(2, 1, 1));
+        assert_eq!(vote_granted(&actions), Some(false));
+        assert_eq!(node.term(), 2);
+        let actions = node.on_message(node_addr(6003), request(2, 5, 0));
+        assert_eq!(vote_granted(&actions), Some(false));
+        let actions = node.on_message(node_addr(6003), request(2, 2, 1));
+        assert_eq!(vote_granted(&actions), Some(true));
+    }
+
+    #[test]
+    fn entries_that_conflict_with_the_leader_are_truncated() {
+        let mut node = RaftNode::new(node_addr(6001), vec![node_addr(6002), node_addr(6003)]);
+        let append = |term, prev_index, prev_term, entries| RaftMessage::AppendEntries {
+            term,
+            prev_index,
+            prev_term,
+            entries,
+            commit: 0,
+        };
+        node.on_message(
+            node_addr(6002),
+            append(
+                1,
+                0,
+                0,
+                vec![entry(1, 9001), entry(1, 9002), entry(1, 9003)],
+            ),
+        );
+
+        // Sin la entrada anterior se rechaza y se sugiere desde donde seguir
+        let actions = node.on_message(node_addr(6003), append(2, 5, 2, vec![entry(2, 9005)]));
+        assert_eq!(append_reply(&actions), Some((false, 3)));
+        let actions = node.on_message(node_addr(6003), append(2, 2, 2, vec![entry(2, 9005)]));
+        assert_eq!(append_reply(&actions), Some((false, 1)));
+
+        let actions = node.on_message(node_addr(6003), append(2, 1, 1, vec![entry(2, 9004)]));
+        assert_eq!(append_reply(&actions), Some((true, 2)));
+        assert_eq!(log_of(&node), vec![(1, Some(9001)), (2, Some(9004))]);
+
+        // Un AppendEntries atrasado que ya coincide no borra nada
+        let actions = node.on_message(node_addr(6003), append(2, 0, 0, vec![entry(1, 9001)]));
+        assert_eq!(append_reply(&actions), Some((true, 1)));
+        assert_eq!(log_of(&node), vec![(1, Some(9001)), (2, Some(9004))]);
+    }
+
+    #[test]
+    fn commit_advances_with_a_majority_and_applies_in_order() {
+        let mut group = Group::new(&[6001, 6002, 6003]);
+        group.campaign(6001);
+        group.crashed.extend([node_addr(6002), node_addr(6003)]);
+        assert!(group.propose(6001, 9001));
+        assert!(group.propose(6001, 9002));
+        // Sin la mayoria no se confirma nada
+        assert_eq!(group.node(6001).commit, 1);
+        assert!(group.applied.values().all(|ports| ports.is_empty()));
+
+        group.crashed.retain(|addr| *addr != node_addr(6002));
+        group.heartbeat(6001);
+        assert_eq!(group.node(6001).commit, 3);
+        assert_eq!(group.applied[&node_addr(6001)], vec![9001, 9002]);
+        // El seguidor aplica cuando se entera de lo confirmado
+        group.heartbeat(6001);
+        assert_eq!(group.applied[&node_addr(6002)], vec![9001, 9002]);
+        assert!(group
+            .applied
+            .get(&node_addr(6003))
+            .is_none_or(|ports| ports.is_empty()));
+
+        assert!(!group.propose(6002, 9003));
+    }
+
+    #[test]
+    fn every_change_is_persisted_before_the_reply() {
+        let mut node = RaftNode::new(node_addr(6001), vec![node_addr(6002)]);
+        let actions = node.on_message(
+            node_addr(6002),
+            RaftMessage::RequestVote {
+                term: 3,
+                last_index: 0,
+                last_term: 0,
+            },
+        );
+        assert!(matches!(
+            actions.as_slice(),
+            [RaftAction::Persist(records), RaftAction::Send(..)]
+                if matches!(records.as_slice(), [RaftRecord::Vote { term: 3, voted_for: Some(_) }])
+        ));
+
+        let actions = node.on_message(
+            node_addr(6002),
+            RaftMessage::AppendEntries {
+                term: 3,
+                prev_index: 0,
+                prev_term: 0,
+                entries: vec![entry(3, 9001)],
+                commit: 0,
+            },
+        );
+        assert!(matches!(
+            actions.as_slice(),
+            [RaftAction::Persist(records), RaftAction::Send(..)]
+                if matches!(records.as_slice(), [RaftRecord::Entries { from: 1, entries }] if entries.len() == 1)
+        ));
+        // Un latido sin cambios no escribe nada
+        let actions = node.on_message(
+            node_addr(6002),
+            RaftMessage::AppendEntries {
+                term: 3,
+                prev_index: 1,
+                prev_term: 3,
+                entries: Vec::new(),
+                commit: 0,
+            },
+        );
+        assert!(matches!(actions.as_slice(), [RaftAction::Send(..)]));
+    }
+
+    #[test]
+    fn restores_term_vote_and_log_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("raft_restore_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let (storage, records) = RaftStorage::open(path).unwrap();
+        assert!(records.is_empty());
+
+        let mut node = RaftNode::new(node_addr(6001), vec![node_addr(6002), node_addr(6003)]);
+        let messages = [
+            RaftMessage::RequestVote {
+                term: 2,
+                last_index: 0,
+                last_term: 0,
+            },
+            RaftMessage::AppendEntries {
+                term: 2,
+                prev_index: 0,
+                prev_term: 0,
+                entries: vec![entry(2, 9001), entry(2, 9002)],
+                commit: 0,
+            },
+        ];
+        for message in messages {
+            for action in node.on_message(node_addr(6002), message) {
+                if let RaftAction::Persist(records) = action {
+                    storage.save(&records).unwrap();
+                }
+            }
+        }
+        drop(storage);
+        // Se cayo mientras escribia una linea
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"{\"record\":\"Vote\",\"te").unwrap();
+
+        let (_, records) = RaftStorage::open(path).unwrap();
+        let mut restored = RaftNode::restore(node_addr(6001), vec![node_addr(6002)], records);
+        assert_eq!(restored.term(), 2);
+        assert_eq!(restored.voted_for, Some(node_addr(6002)));
+        assert_eq!(log_of(&restored), vec![(2, Some(9001)), (2, Some(9002))]);
+        let actions = restored.on_message(
+            node_addr(6003),
+            RaftMessage::RequestVote {
+                term: 2,
+                last_index: 2,
+                last_term: 2,
+            },
+        );
+        assert_eq!(vote_granted(&actions), Some(false));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -15,6 +15,21 @@ pub struct RecordList {
 }
 
 impl Record {
+    pub fn new(
+        id: usize,
+        client_id: usize,
+        ice_creams: Vec<String>,
+        size_order: f32,
+        cash_card: usize,
+    ) -> Record {
+        Record {
+            id,
+            client_id,
+            ice_creams,
+            size_order,
+            cash_card,
+        }
+    }
     pub fn get_id(&self) -> usize {
         self.id
     }
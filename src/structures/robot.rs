@@ -1,13 +1,23 @@
 use crate::common::log::{LogLevel, Logger};
-use crate::common::protocol::DTO;
+use crate::common::protocol::{OrderKey, DTO};
+use crate::common::udp;
 use crate::defines::ack::Ack;
 use crate::structures::ack_manager::AckManager;
-use crate::structures::leader_order_processing::LeaderOrderProcessing;
+use crate::structures::election::{Candidate, ElectionAction, ElectionState};
+use crate::structures::leader_order_processing::{
+    Apply, DrainRobot, ExtendDeadline, Follow, HandOver, Lead, LeaderOrderProcessing,
+    LeaderSnapshot, OrderFinished, Propose, RegisterRobot, RemoveRobot, ReserveStock,
+};
+use crate::structures::raft::{RaftAction, RaftMessage, RaftNode, RaftStorage};
+use crate::structures::robot_config::RobotConfig;
 use actix::prelude::*;
 use rand::Rng;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::time::Duration;
-use std::{io, thread};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -19,7 +29,8 @@ pub struct Msg {
 #[derive(Message)]
 #[rtype(result = "()")]
 struct Election {
-    id: usize,
+    candidate: Candidate,
+    epoch: u64,
     sender: SocketAddr,
 }
 
@@ -27,13 +38,82 @@ struct Election {
 #[rtype(result = "()")]
 struct Leader {
     leader_id: usize,
+    epoch: u64,
+    endpoint: Option<SocketAddr>,
     sender: SocketAddr,
 }
 
+/// Consulta de una interfaz (o un pedido mandado a un robot que no atiende
+/// pedidos) que se responde con el lider vigente.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct WhoIsLeader {
+    addr: SocketAddr,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 struct Announce {
     id: usize,
+    capacity: usize,
+    incarnation: u64,
+    addr: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Join {
+    id: usize,
+    addr: SocketAddr,
+    sender: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Members {
+    members: Vec<(usize, SocketAddr)>,
+    sender: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Leave {
+    id: usize,
+    sender: SocketAddr,
+}
+
+/// Pide al robot que se retire ordenadamente: devuelve los pedidos que no
+/// empezo, termina los que tiene en curso y avisa su baja antes de frenar.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Drain;
+
+/// Pide al lider que deje el rol y traspase su estado a otro robot. Sin
+/// sucesor elegido se usa el miembro de mayor id.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StepDown {
+    pub successor: Option<usize>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Handover {
+    snapshot: LeaderSnapshot,
+    sender: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct RaftRpc {
+    message: RaftMessage,
+    sender: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Draining {
+    handed_back: Vec<OrderKey>,
     addr: SocketAddr,
 }
 
@@ -48,27 +128,41 @@ struct Hello {
 #[rtype(result = "()")]
 struct WorkMessage {
     dto: DTO,
+    epoch: u64,
     addr: SocketAddr,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 struct AvailabilityMessage {
+    key: OrderKey,
     addr: SocketAddr,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 struct UseStock {
+    key: OrderKey,
     ice_cream: Vec<String>,
     mount: f64,
+    epoch: u64,
     addr: SocketAddr,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 struct StockResult {
+    key: OrderKey,
     result: bool,
+    epoch: u64,
+    addr: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ProgressMessage {
+    key: OrderKey,
+    extension: Duration,
     addr: SocketAddr,
 }
 
@@ -79,15 +173,83 @@ struct AckRobot {
     type_ack: String,
 }
 
+// Cada cuanto se revisan las preparaciones en curso
+const PREPARATION_TICK: Duration = Duration::from_secs(1);
+// Tiempo maximo para terminar los pedidos en curso al retirarse
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// Margen para que lleguen los acks de la baja antes de frenar el actor
+const LEAVE_GRACE: Duration = Duration::from_secs(1);
+// Tiempo que espera el lider saliente a que el sucesor tome el rol
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(10);
+// Cada cuanto reintenta el sucesor tomar su puerto de lider
+const BIND_RETRY: Duration = Duration::from_millis(200);
+// Plazo en el que un candidato mejor tiene que contestar (bully)
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(2);
+// Reloj de Raft: latidos y plazos de eleccion se miden en ticks
+const RAFT_TICK: Duration = Duration::from_millis(100);
+// Mensajes que se le mandan al lider y hay que redirigir si cambia
+// (los pedidos de stock no: se vuelven a pedir con la epoca nueva)
+const LEADER_BOUND: [&str; 3] = ["Availability", "Progress", "Draining"];
+
+struct Preparation {
+    order: DTO,
+    result: bool,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Preparation {
+    fn new(order: DTO, result: bool, duration: Duration) -> Preparation {
+        Preparation {
+            order,
+            result,
+            started: Instant::now(),
+            duration,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.started.elapsed() >= self.duration
+    }
+
+    fn progress(&self) -> u128 {
+        (self.started.elapsed().as_millis() * 100 / self.duration.as_millis().max(1)).min(100)
+    }
+}
+
+/// Parsea un miembro del cluster con el formato `id@ip:puerto`.
+fn parse_member(member: &str) -> Option<(usize, SocketAddr)> {
+    let (id, addr) = member.split_once('@')?;
+    Some((id.parse().ok()?, addr.parse().ok()?))
+}
+
 pub struct Robot {
     id: usize,
-    socket: UdpSocket,
+    socket: Arc<UdpSocket>,
     leader_id: Option<SocketAddr>,
-    peers: Vec<SocketAddr>,
+    leader_endpoint: Option<SocketAddr>,
+    leader_addr: SocketAddr,
+    epoch: u64,
+    seeds: Vec<SocketAddr>,
+    peers: BTreeMap<usize, SocketAddr>,
+    interfaces: BTreeSet<SocketAddr>,
     im_leader: bool,
-    leader_order_processing: LeaderOrderProcessing,
+    leader_order_processing: Option<Addr<LeaderOrderProcessing>>,
+    capacity: usize,
+    incarnation: u64,
+    priority: u32,
+    prefer_fresh_state: bool,
+    election: ElectionState,
+    pending_orders: VecDeque<DTO>,
     current_order: Option<DTO>,
+    preparing: Vec<Preparation>,
+    // Pedidos cuyo resultado se esta entregando, con la interfaz duena
+    delivering: HashMap<OrderKey, SocketAddr>,
     current_order_result: Option<bool>,
+    draining_since: Option<Instant>,
+    handing_over: Option<(SocketAddr, LeaderSnapshot)>,
+    raft: Option<RaftNode>,
+    raft_storage: Option<RaftStorage>,
     ack_manager: AckManager,
 }
 
@@ -95,77 +257,595 @@ impl Clone for Robot {
     fn clone(&self) -> Robot {
         Robot {
             id: self.id,
-            socket: self.socket.try_clone().unwrap(),
+            socket: Arc::clone(&self.socket),
             leader_id: self.leader_id,
+            leader_endpoint: self.leader_endpoint,
+            leader_addr: self.leader_addr,
+            epoch: self.epoch,
+            seeds: self.seeds.clone(),
             peers: self.peers.clone(),
+            interfaces: self.interfaces.clone(),
             im_leader: self.im_leader,
             leader_order_processing: self.leader_order_processing.clone(),
+            capacity: self.capacity,
+            incarnation: self.incarnation,
+            priority: self.priority,
+            prefer_fresh_state: self.prefer_fresh_state,
+            election: self.election.clone(),
+            pending_orders: self.pending_orders.clone(),
             current_order: self.current_order.clone(),
-            current_order_result: self.current_order_result.clone(),
+            preparing: Vec::new(),
+            delivering: self.delivering.clone(),
+            current_order_result: self.current_order_result,
+            draining_since: self.draining_since,
+            handing_over: self.handing_over.clone(),
+            raft: self.raft.clone(),
+            raft_storage: self.raft_storage.clone(),
             ack_manager: self.ack_manager.clone(),
         }
     }
 }
 
 impl Robot {
-    pub fn new(id: usize) -> io::Result<Robot> {
-        let socket = UdpSocket::bind(format!("127.0.0.1:{}", 6000 + id))?;
-        let socket_clone = socket.try_clone().unwrap();
+    pub fn new(config: RobotConfig) -> io::Result<Robot> {
+        let socket = std::net::UdpSocket::bind(config.addr)?;
+        let socket = udp::into_async(socket)?;
+        let ack_manager = AckManager::new(Arc::clone(&socket));
+        // El grupo de Raft es el robot con sus semillas. Retoma el mandato, el
+        // voto y el log que guardo antes de caerse
+        let (raft, raft_storage) = if config.raft {
+            let (storage, records) = RaftStorage::open(&config.raft_log)?;
+            let node = RaftNode::restore(config.addr, config.seeds.clone(), records);
+            (Some(node), Some(storage))
+        } else {
+            (None, None)
+        };
 
         let robot = Robot {
-            id,
+            id: config.id,
             socket,
             leader_id: None,
-            peers: Vec::new(),
+            leader_endpoint: None,
+            leader_addr: config.leader_addr,
+            epoch: 0,
+            seeds: config.seeds,
+            peers: BTreeMap::new(),
+            interfaces: BTreeSet::new(),
             im_leader: false,
-            leader_order_processing: LeaderOrderProcessing::new(), // Pass the 'id' argument to the 'new' function
+            leader_order_processing: None,
+            capacity: config.capacity.max(1),
+            // Cambia en cada arranque, asi el lider reconoce un reinicio
+            incarnation: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            priority: config.priority,
+            prefer_fresh_state: config.prefer_fresh_state,
+            election: ElectionState::new(config.election),
+            pending_orders: VecDeque::new(),
             current_order: None,
+            preparing: Vec::new(),
+            delivering: HashMap::new(),
             current_order_result: None,
-            ack_manager: AckManager::new(socket_clone),
+            draining_since: None,
+            handing_over: None,
+            raft,
+            raft_storage,
+            ack_manager,
         };
 
         Ok(robot)
     }
 
     fn send_message(&self, message: String, addr: SocketAddr) -> io::Result<usize> {
-        self.socket.send_to(message.as_bytes(), addr)
+        udp::send_message(&self.socket, message.as_str(), addr)
+    }
+
+    /// Como se presenta este robot en una eleccion. El estado se mide por la
+    /// ultima epoca que conoce.
+    fn candidate(&self) -> Candidate {
+        let freshness = if self.prefer_fresh_state {
+            self.epoch
+        } else {
+            0
+        };
+        Candidate::new(self.id, self.priority, freshness)
+    }
+
+    fn peer_ids(&self) -> Vec<usize> {
+        self.peers.keys().copied().collect()
     }
 
-    fn handle_election(&mut self, msg: Election) {
+    /// Empieza una eleccion proponiendose como candidato. Con Raft el lider
+    /// lo elige el grupo de Raft.
+    fn start_election(&mut self, ctx: &mut Context<Self>) {
+        if self.election.is_electing() || self.im_leader || self.raft.is_some() {
+            return;
+        }
+        Logger.log(
+            LogLevel::Info,
+            format!("[Robot {}] Starting an election", self.id).as_str(),
+        );
+        let actions = self
+            .election
+            .start(self.candidate(), self.epoch, &self.peer_ids());
+        self.apply_election(actions, ctx);
+    }
+
+    /// Ejecuta lo que pide el algoritmo de eleccion configurado.
+    fn apply_election(&mut self, actions: Vec<ElectionAction>, ctx: &mut Context<Self>) {
+        for action in actions {
+            match action {
+                ElectionAction::Send(id, candidate, epoch) => {
+                    if let Some(addr) = self.peers.get(&id).copied() {
+                        let msg = format!("Election:{},{}", epoch, candidate.serialize());
+                        let _ = self.send_message(msg.clone(), addr);
+                        self.ack_manager.add(
+                            Ack::new(addr, msg, "Election".to_string()),
+                            Duration::from_secs(5),
+                        );
+                    }
+                }
+                ElectionAction::Won(epoch) => self.win_election(epoch),
+                ElectionAction::StartTimer => {
+                    ctx.run_later(ELECTION_TIMEOUT, |act, ctx| {
+                        let actions = act.election.on_timeout(act.candidate(), &act.peer_ids());
+                        act.apply_election(actions, ctx);
+                    });
+                }
+            }
+        }
+    }
+
+    fn handle_election(&mut self, msg: Election, ctx: &mut Context<Self>) {
         Logger.log(
             LogLevel::Info,
             format!(
-                "[Robot {}] received election message from Robot {}",
-                self.id, msg.id
+                "[Robot {}] received election message with candidate Robot {}",
+                self.id, msg.candidate.id
             )
             .as_str(),
         );
-        if self.id > msg.id {
-            if let Some(next_peer) = self.peers.iter().find(|&&peer| peer != msg.sender) {
-                let _ = self.send_message(format!("Election:{}", self.id), *next_peer);
-            } else {
-                self.leader_id = Some(self.socket.local_addr().unwrap());
-                for peer in self.peers.iter() {
-                    let _ = self.send_message(format!("Leader:{}", self.id), *peer);
-                }
-            }
+        let _ = self.send_message("Ack:Election".to_string(), msg.sender);
+        if self.im_leader {
+            // La eleccion ya tiene ganador: se le avisa a quien pregunta
+            let msg_leader = self.leader_message();
+            let _ = self.send_message(msg_leader.clone(), msg.sender);
+            self.ack_manager.add(
+                Ack::new(msg.sender, msg_leader, "Leader".to_string()),
+                Duration::from_secs(5),
+            );
+            return;
+        }
+        let actions =
+            self.election
+                .on_election(self.candidate(), msg.candidate, msg.epoch, &self.peer_ids());
+        self.apply_election(actions, ctx);
+    }
+
+    fn win_election(&mut self, epoch: u64) {
+        self.epoch = epoch;
+        Logger.log(
+            LogLevel::LeaderInfo,
+            format!("[Robot {}] Won the election", self.id).as_str(),
+        );
+        if self.become_leader() {
+            self.register_robot(
+                self.socket.local_addr().unwrap(),
+                self.capacity,
+                self.incarnation,
+            );
+            self.announce_leadership();
         }
     }
 
+    fn leader_message(&self) -> String {
+        format!("Leader:{},{},{}", self.id, self.epoch, self.leader_addr)
+    }
+
+    /// Responde a quien pregunta por el lider con su id, epoca y el puerto en
+    /// el que atiende pedidos. Si todavia no se conoce, no responde.
+    fn handle_who_is_leader(&mut self, msg: WhoIsLeader) {
+        // Quien pregunta es una interfaz: se la recuerda para avisarle si
+        // este robot pasa a ser lider
+        self.interfaces.insert(msg.addr);
+        if let Some(endpoint) = self.leader_endpoint {
+            let (epoch, leader) = self.current_term();
+            let reply = format!("Leader:{},{},{}", leader, epoch, endpoint);
+            let _ = self.send_message(reply, msg.addr);
+        }
+    }
+
+    /// Mandato del lider que se sigue: su epoca y, para desempatar, su id.
+    fn current_term(&self) -> (u64, usize) {
+        let leader = match self.leader_id {
+            _ if self.im_leader => self.id,
+            Some(addr) => self
+                .peers
+                .iter()
+                .find(|(_, peer)| **peer == addr)
+                .map_or(0, |(id, _)| *id),
+            None => 0,
+        };
+        (self.epoch, leader)
+    }
+
     fn handle_leader(&mut self, msg: Leader) {
         Logger.log(
             LogLevel::Info,
             format!(
-                "[Robot {}] received leader message from Robot {}",
-                self.id, msg.leader_id
+                "[Robot {}] received leader message from Robot {} for epoch {}",
+                self.id, msg.leader_id, msg.epoch
             )
             .as_str(),
         );
-        self.leader_id = Some(msg.sender);
-        if !self.peers.contains(&msg.sender) {
-            self.peers.push(msg.sender);
-        }
         let _ = self.send_message("Ack:Leader".to_string(), msg.sender);
+        // Un lider de una epoca vieja (o de la misma epoca con menor id) no
+        // se acepta; si este robot es el lider vigente se lo hace saber
+        if (msg.epoch, msg.leader_id) < self.current_term() {
+            Logger.log(
+                LogLevel::Error,
+                format!(
+                    "[Robot {}] Ignoring leader {} from stale epoch {}",
+                    self.id, msg.leader_id, msg.epoch
+                )
+                .as_str(),
+            );
+            if self.im_leader {
+                let msg_leader = self.leader_message();
+                let _ = self.send_message(msg_leader.clone(), msg.sender);
+                self.ack_manager.add(
+                    Ack::new(msg.sender, msg_leader, "Leader".to_string()),
+                    Duration::from_secs(5),
+                );
+            }
+            return;
+        }
+        if self.im_leader {
+            self.resign();
+        }
+        self.election.on_leader(msg.epoch);
+        let changed = self.leader_id != Some(msg.sender) || msg.epoch > self.epoch;
+        self.epoch = msg.epoch;
+        self.leader_endpoint = msg.endpoint;
+        self.add_member(msg.leader_id, msg.sender);
+        if changed {
+            self.follow_leader(msg.sender);
+            // Un lider nuevo puede no conocer a este robot
+            self.announce_to(msg.sender);
+        }
+    }
+
+    /// Pasa a seguir a otro lider: lo que quedo sin confirmar por el anterior
+    /// va al nuevo, y el pedido que esperaba stock se vuelve a pedir con la
+    /// epoca nueva.
+    fn follow_leader(&mut self, addr: SocketAddr) {
+        if let Some(old_leader) = self.leader_id {
+            if old_leader != addr {
+                self.ack_manager.redirect(old_leader, addr, &LEADER_BOUND);
+            }
+            self.ack_manager.remove("UseStock".to_string(), old_leader);
+        }
+        self.leader_id = Some(addr);
+        if let Some(dto) = self.current_order.clone() {
+            self.prepare_order(&dto);
+        }
+    }
+
+    /// Deja de ser lider porque aparecio uno de una epoca mas nueva. El
+    /// estado del lider se descarta.
+    fn resign(&mut self) {
+        Logger.log(
+            LogLevel::LeaderInfo,
+            format!(
+                "[Robot {}] Fenced by a newer leader, stepping aside",
+                self.id
+            )
+            .as_str(),
+        );
+        match (&self.raft, &self.leader_order_processing) {
+            // La replica se queda: sigue aplicando lo que confirme el grupo
+            (Some(_), Some(replica)) => replica.do_send(Follow),
+            _ => {
+                if let Some(leader) = self.leader_order_processing.take() {
+                    leader.do_send(HandOver);
+                }
+            }
+        }
+        self.im_leader = false;
+        self.leader_id = None;
+        self.leader_endpoint = None;
+    }
+
+    /// Avisa a las interfaces conocidas que este robot es el nuevo lider, para
+    /// que le reenvien los pedidos que no tenian confirmados.
+    fn notify_interfaces(&mut self) {
+        let msg = format!("New{}", self.leader_message());
+        for interface in self.interfaces.clone() {
+            let _ = self.send_message(msg.clone(), interface);
+            self.ack_manager.add(
+                Ack::new(interface, msg.clone(), "NewLeader".to_string()),
+                Duration::from_secs(5),
+            );
+        }
+    }
+
+    /// Avisa a todo el cluster que este robot es el lider de la epoca actual.
+    fn announce_leadership(&mut self) {
+        let msg_leader = self.leader_message();
+        for peer in self.peers.values().copied().collect::<Vec<SocketAddr>>() {
+            let _ = self.send_message(msg_leader.clone(), peer);
+            self.ack_manager.add(
+                Ack::new(peer, msg_leader.clone(), "Leader".to_string()),
+                Duration::from_secs(5),
+            );
+        }
+    }
+
+    /// Deja el rol de lider: frena el actor del lider, que libera su puerto y
+    /// devuelve su estado, y se lo manda al sucesor.
+    fn step_down(&mut self, msg: StepDown, ctx: &mut Context<Self>) {
+        if self.raft.is_some() {
+            Logger.log(
+                LogLevel::Error,
+                format!(
+                    "[Robot {}] With Raft the leader changes through a Raft election",
+                    self.id
+                )
+                .as_str(),
+            );
+            return;
+        }
+        let leader = match (&self.leader_order_processing, self.handing_over.is_some()) {
+            (Some(leader), false) if self.im_leader => leader.clone(),
+            _ => {
+                Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] Not the leader, cannot step down", self.id).as_str(),
+                );
+                return;
+            }
+        };
+        let successor = match msg.successor {
+            Some(id) => self.peers.get(&id).copied(),
+            None => self.peers.values().next_back().copied(),
+        };
+        let successor = match successor {
+            Some(successor) => successor,
+            None => {
+                Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] No successor to step down to", self.id).as_str(),
+                );
+                return;
+            }
+        };
+        Logger.log(
+            LogLevel::LeaderInfo,
+            format!("Stepping down, handing over to {}", successor).as_str(),
+        );
+        // Desde ahora lo que le toca al lider va al sucesor, que no lo
+        // confirma hasta tener el puerto
+        self.leader_order_processing = None;
+        self.im_leader = false;
+        self.leader_id = Some(successor);
+        self.leader_endpoint = None;
+        leader
+            .send(HandOver)
+            .into_actor(self)
+            .map(move |result, act, ctx| match result {
+                Ok(snapshot) => act.send_handover(successor, snapshot, ctx),
+                Err(e) => Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] Could not stop the leader: {}", act.id, e).as_str(),
+                ),
+            })
+            .wait(ctx);
+    }
+
+    fn send_handover(
+        &mut self,
+        successor: SocketAddr,
+        snapshot: LeaderSnapshot,
+        ctx: &mut Context<Self>,
+    ) {
+        let msg = format!("Handover:{}", serde_json::to_string(&snapshot).unwrap());
+        let _ = self.send_message(msg.clone(), successor);
+        self.ack_manager.add(
+            Ack::new(successor, msg, "Handover".to_string()),
+            Duration::from_secs(5),
+        );
+        self.handing_over = Some((successor, snapshot));
+        // Si el sucesor no confirma a tiempo, se vuelve a tomar el rol
+        ctx.run_later(HANDOVER_TIMEOUT, |act, _ctx| {
+            if let Some((successor, snapshot)) = act.handing_over.take() {
+                Logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "[Robot {}] Successor {} did not take over, resuming leadership",
+                        act.id, successor
+                    )
+                    .as_str(),
+                );
+                act.ack_manager.remove("Handover".to_string(), successor);
+                act.take_leadership(snapshot);
+            }
+        });
+    }
+
+    /// El sucesor levanta el actor del lider con el estado recibido. Si su
+    /// puerto de lider sigue ocupado, reintenta.
+    fn handle_handover(&mut self, msg: Handover, ctx: &mut Context<Self>) {
+        if self.im_leader {
+            let _ = self.send_message("Ack:Handover".to_string(), msg.sender);
+            return;
+        }
+        let epoch = self.epoch.max(msg.snapshot.epoch) + 1;
+        match LeaderOrderProcessing::from_snapshot(msg.snapshot.clone(), epoch, self.leader_addr) {
+            Ok(leader) => {
+                Logger.log(
+                    LogLevel::LeaderInfo,
+                    format!(
+                        "[Robot {}] Took over the leadership at epoch {}",
+                        self.id, epoch
+                    )
+                    .as_str(),
+                );
+                self.leader_order_processing = Some(leader.start());
+                self.leader_endpoint = Some(self.leader_addr);
+                self.epoch = epoch;
+                self.im_leader = true;
+                self.follow_leader(self.socket.local_addr().unwrap());
+                let _ = self.send_message("Ack:Handover".to_string(), msg.sender);
+                self.announce_leadership();
+                self.notify_interfaces();
+            }
+            Err(_) => {
+                ctx.run_later(BIND_RETRY, move |act, ctx| act.handle_handover(msg, ctx));
+            }
+        }
+    }
+
+    /// Vuelve a ser lider con un estado dado, por ejemplo si fallo el traspaso.
+    fn take_leadership(&mut self, snapshot: LeaderSnapshot) {
+        let epoch = self.epoch.max(snapshot.epoch) + 1;
+        match LeaderOrderProcessing::from_snapshot(snapshot, epoch, self.leader_addr) {
+            Ok(leader) => {
+                self.leader_order_processing = Some(leader.start());
+                self.leader_endpoint = Some(self.leader_addr);
+                self.epoch = epoch;
+                self.im_leader = true;
+                self.follow_leader(self.socket.local_addr().unwrap());
+                self.announce_leadership();
+                self.notify_interfaces();
+            }
+            Err(e) => Logger.log(
+                LogLevel::Error,
+                format!("[Robot {}] Could not take the leader port: {}", self.id, e).as_str(),
+            ),
+        }
+    }
+
+    fn handle_handover_ack(&mut self, addr: SocketAddr) {
+        self.ack_manager.remove("Handover".to_string(), addr);
+        if self.handing_over.take().is_some() {
+            Logger.log(
+                LogLevel::Info,
+                format!("[Robot {}] Stepped down, now working as a robot", self.id).as_str(),
+            );
+        }
+    }
+
+    /// Levanta la replica del estado del lider en su puerto de lider. Si el
+    /// puerto sigue ocupado, reintenta.
+    fn start_replica(&mut self, ctx: &mut Context<Self>) {
+        match LeaderOrderProcessing::replica(self.leader_addr, ctx.address().recipient()) {
+            Ok(replica) => self.leader_order_processing = Some(replica.start()),
+            Err(e) => {
+                Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] Could not start the replica: {}", self.id, e).as_str(),
+                );
+                ctx.run_later(BIND_RETRY, |act, ctx| act.start_replica(ctx));
+            }
+        }
+    }
+
+    fn raft_tick(&mut self) {
+        if let Some(raft) = &mut self.raft {
+            let actions = raft.tick();
+            self.apply_raft(actions);
+        }
+    }
+
+    fn handle_raft(&mut self, msg: RaftRpc) {
+        if let Some(raft) = &mut self.raft {
+            let actions = raft.on_message(msg.sender, msg.message);
+            self.apply_raft(actions);
+        }
+    }
+
+    /// Agrega al log un cambio que propuso la replica. Si este robot ya no es
+    /// el lider de Raft el cambio se descarta y quien lo pidio lo reintenta.
+    fn propose(&mut self, msg: Propose) {
+        let actions = match &mut self.raft {
+            Some(raft) => raft.propose(msg.command),
+            None => None,
+        };
+        match actions {
+            Some(actions) => self.apply_raft(actions),
+            None => Logger.log(
+                LogLevel::Error,
+                format!("[Robot {}] Not the Raft leader, dropping command", self.id).as_str(),
+            ),
+        }
+    }
+
+    /// Ejecuta lo que pide el nodo de Raft.
+    fn apply_raft(&mut self, actions: Vec<RaftAction>) {
+        for action in actions {
+            match action {
+                RaftAction::Persist(records) => {
+                    let saved = match &self.raft_storage {
+                        Some(storage) => storage.save(&records),
+                        None => Ok(()),
+                    };
+                    // Sin el estado en disco no se contesta: el resto lo
+                    // reintenta Raft
+                    if let Err(e) = saved {
+                        Logger.log(
+                            LogLevel::Error,
+                            format!("[Robot {}] Could not save Raft state: {}", self.id, e)
+                                .as_str(),
+                        );
+                        return;
+                    }
+                }
+                RaftAction::Send(addr, message) => {
+                    // Sin ack: Raft reintenta solo con cada latido
+                    let msg = format!("Raft:{}", serde_json::to_string(&message).unwrap());
+                    let _ = self.send_message(msg, addr);
+                }
+                RaftAction::Apply(commands) => {
+                    if let Some(replica) = &self.leader_order_processing {
+                        replica.do_send(Apply { commands });
+                    }
+                }
+                RaftAction::BecameLeader(term) => self.lead_raft(term),
+                RaftAction::SteppedDown(term) => {
+                    if self.im_leader {
+                        self.resign();
+                    }
+                    self.epoch = self.epoch.max(term);
+                }
+            }
+        }
+    }
+
+    /// Gano un mandato de Raft: la replica pasa a atender pedidos con el
+    /// mandato como epoca y se avisa a robots e interfaces.
+    fn lead_raft(&mut self, term: u64) {
+        Logger.log(
+            LogLevel::LeaderInfo,
+            format!(
+                "[Robot {}] Leading the Raft group at term {}",
+                self.id, term
+            )
+            .as_str(),
+        );
+        if let Some(replica) = &self.leader_order_processing {
+            replica.do_send(Lead { epoch: term });
+        }
+        self.epoch = term;
+        self.im_leader = true;
+        self.leader_endpoint = Some(self.leader_addr);
+        self.follow_leader(self.socket.local_addr().unwrap());
+        self.register_robot(
+            self.socket.local_addr().unwrap(),
+            self.capacity,
+            self.incarnation,
+        );
+        self.announce_leadership();
+        self.notify_interfaces();
     }
 
     fn announce(&mut self) {
@@ -177,20 +857,213 @@ impl Robot {
             )
             .as_str(),
         );
-        for i in 1..10 {
-            if i == self.id {
-                continue;
-            }
-            let addr = format!("127.0.0.1:{}", 6000 + i);
-            if let Some(socket_addr) = addr.to_socket_addrs().unwrap().next() {
-                let msg = format!("Announce:{}", self.id);
-                let _ = self.send_message(msg.clone(), socket_addr);
-                self.ack_manager.add(
-                    Ack::new(socket_addr, msg.clone().to_string(), "Announce".to_string()),
-                    Duration::from_secs(5),
+        for seed in self.seeds.clone() {
+            self.announce_to(seed);
+        }
+    }
+
+    fn announce_to(&mut self, addr: SocketAddr) {
+        let msg = format!(
+            "Announce:{},{},{}",
+            self.id, self.capacity, self.incarnation
+        );
+        let _ = self.send_message(msg.clone(), addr);
+        self.ack_manager.add(
+            Ack::new(addr, msg, "Announce".to_string()),
+            Duration::from_secs(5),
+        );
+    }
+
+    /// Agrega un miembro al cluster. Si no se lo conocia, se propaga el alta
+    /// al resto de los miembros. Devuelve true si es un miembro nuevo.
+    fn add_member(&mut self, id: usize, addr: SocketAddr) -> bool {
+        if id == self.id || self.peers.get(&id) == Some(&addr) {
+            return false;
+        }
+        Logger.log(
+            LogLevel::Info,
+            format!(
+                "[Robot {}] Robot {} joins the cluster at {}",
+                self.id, id, addr
+            )
+            .as_str(),
+        );
+        self.peers.insert(id, addr);
+        self.gossip(format!("Join:{}@{}", id, addr), "Join", id);
+        true
+    }
+
+    /// Saca un miembro del cluster y propaga la baja. Devuelve true si se lo
+    /// conocia.
+    fn remove_member(&mut self, id: usize) -> bool {
+        match self.peers.remove(&id) {
+            Some(addr) => {
+                Logger.log(
+                    LogLevel::Info,
+                    format!(
+                        "[Robot {}] Robot {} leaves the cluster ({})",
+                        self.id, id, addr
+                    )
+                    .as_str(),
                 );
+                self.gossip(format!("Leave:{}", id), "Leave", id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Envia un mensaje de membresia a todos los miembros salvo al afectado.
+    fn gossip(&mut self, msg: String, type_msg: &str, except: usize) {
+        let peers: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(id, _)| **id != except)
+            .map(|(_, addr)| *addr)
+            .collect();
+        for addr in peers {
+            let _ = self.send_message(msg.clone(), addr);
+            self.ack_manager.add(
+                Ack::new(addr, msg.clone(), type_msg.to_string()),
+                Duration::from_secs(5),
+            );
+        }
+    }
+
+    fn members_message(&self) -> String {
+        let mut members = vec![format!("{}@{}", self.id, self.socket.local_addr().unwrap())];
+        members.extend(
+            self.peers
+                .iter()
+                .map(|(id, addr)| format!("{}@{}", id, addr)),
+        );
+        format!("Members:{}", members.join(";"))
+    }
+
+    fn handle_join(&mut self, msg: Join) {
+        let _ = self.send_message("Ack:Join".to_string(), msg.sender);
+        self.add_member(msg.id, msg.addr);
+    }
+
+    fn handle_members(&mut self, msg: Members) {
+        let _ = self.send_message("Ack:Members".to_string(), msg.sender);
+        for (id, addr) in msg.members {
+            // A los miembros que no conocia les anuncia su capacidad, asi el
+            // lider lo registra aunque la semilla no fuera el lider
+            if self.add_member(id, addr) {
+                self.announce_to(addr);
+            }
+        }
+    }
+
+    fn handle_leave(&mut self, msg: Leave, ctx: &mut Context<Self>) {
+        let _ = self.send_message("Ack:Leave".to_string(), msg.sender);
+        if let Some(addr) = self.peers.get(&msg.id).copied() {
+            if let Some(leader) = self.leader_actor() {
+                leader.do_send(RemoveRobot { addr });
+            }
+            if self.leader_id == Some(addr) {
+                self.leader_id = None;
+                self.leader_endpoint = None;
+                self.remove_member(msg.id);
+                self.start_election(ctx);
+                return;
+            }
+        }
+        self.remove_member(msg.id);
+    }
+
+    /// Empieza el retiro del robot: devuelve al lider los pedidos en cola y
+    /// le pide que no le asigne mas trabajo.
+    fn start_drain(&mut self) {
+        if self.draining_since.is_some() {
+            return;
+        }
+        self.draining_since = Some(Instant::now());
+        let handed_back: Vec<OrderKey> =
+            self.pending_orders.drain(..).map(|dto| dto.key()).collect();
+        Logger.log(
+            LogLevel::Info,
+            format!(
+                "[Robot {}] Draining, handing back orders {:?}",
+                self.id, handed_back
+            )
+            .as_str(),
+        );
+        if self.im_leader {
+            Logger.log(
+                LogLevel::LeaderInfo,
+                "The leader is leaving, it will hand over before leaving",
+            );
+        }
+        self.send_draining(handed_back);
+    }
+
+    fn send_draining(&mut self, handed_back: Vec<OrderKey>) {
+        if self.im_leader {
+            self.handle_draining(Draining {
+                handed_back,
+                addr: self.socket.local_addr().unwrap(),
+            });
+        } else if let Some(addr) = self.leader_id {
+            let ids: Vec<String> = handed_back.iter().map(|key| key.to_string()).collect();
+            let msg = format!("Draining:{}", ids.join(","));
+            let _ = self.send_message(msg.clone(), addr);
+            self.ack_manager.add(
+                Ack::new(addr, msg, "Draining".to_string()),
+                Duration::from_secs(5),
+            );
+        }
+    }
+
+    fn handle_draining(&mut self, msg: Draining) {
+        if let Some(leader) = self.leader_actor() {
+            leader.do_send(DrainRobot {
+                addr: msg.addr,
+                handed_back: msg.handed_back,
+            });
+            let _ = self.send_message("Ack:Draining".to_string(), msg.addr);
+        }
+    }
+
+    /// Cuando ya no le queda trabajo (o se vencio el plazo) avisa su baja a
+    /// todo el cluster y frena el actor.
+    fn check_drain(&mut self, ctx: &mut Context<Self>) {
+        let since = match self.draining_since {
+            Some(since) => since,
+            None => return,
+        };
+        let idle =
+            self.current_order.is_none() && self.preparing.is_empty() && self.delivering.is_empty();
+        if (!idle && since.elapsed() < DRAIN_TIMEOUT) || self.handing_over.is_some() {
+            return;
+        }
+        // El lider primero se saca del reparto y le pasa su estado a un
+        // sucesor, asi la cola no se pierde. Si el sucesor no lo toma, vuelve
+        // a ser lider y se reintenta en la proxima revision.
+        if self.im_leader && self.raft.is_none() && !self.peers.is_empty() {
+            if let Some(leader) = &self.leader_order_processing {
+                leader.do_send(RemoveRobot {
+                    addr: self.socket.local_addr().unwrap(),
+                });
+            }
+            self.step_down(StepDown { successor: None }, ctx);
+            return;
+        }
+        Logger.log(
+            LogLevel::Info,
+            format!("[Robot {}] Leaving the cluster", self.id).as_str(),
+        );
+        self.draining_since = None;
+        if self.im_leader {
+            if let Some(leader) = &self.leader_order_processing {
+                leader.do_send(RemoveRobot {
+                    addr: self.socket.local_addr().unwrap(),
+                });
             }
         }
+        self.gossip(format!("Leave:{}", self.id), "Leave", self.id);
+        ctx.run_later(LEAVE_GRACE, |_act, ctx| ctx.stop());
     }
 
     fn handle_announce(&mut self, msg: Announce) {
@@ -204,44 +1077,25 @@ impl Robot {
         );
         let msg_ack = "Ack:Announce";
         let _ = self.send_message(msg_ack.to_string(), msg.addr);
-        if !self.peers.contains(&msg.addr) {
-            self.peers.push(msg.addr);
-        }
+        self.add_member(msg.id, msg.addr);
+        let msg_members = self.members_message();
+        let _ = self.send_message(msg_members.clone(), msg.addr);
+        self.ack_manager.add(
+            Ack::new(msg.addr, msg_members, "Members".to_string()),
+            Duration::from_secs(5),
+        );
 
-        if let Some(leader_id) = self.leader_id {
-            if leader_id == self.socket.local_addr().unwrap() {
-                Logger.log(
-                    LogLevel::LeaderInfo,
-                    format!("Informing Robot {} that i`m the leader", msg.id).as_str(),
-                );
-                let msg_leader = format!("Leader:{}", self.id);
-                let _ = self.send_message(msg_leader.clone(), msg.addr);
-                self.ack_manager.add(
-                    Ack::new(
-                        msg.addr,
-                        msg_leader.to_string().clone(),
-                        "Leader".to_string(),
-                    ),
-                    Duration::from_secs(5),
-                );
-                self.leader_order_processing.send_work_to_robot(msg.addr);
-            } else {
-                let msg_hello = format!("Hello:{}", self.id);
-                let _ = self.send_message(msg_hello.clone(), msg.addr);
-                self.ack_manager.add(
-                    Ack::new(msg.addr, msg_hello.to_string().clone(), "Hello".to_string()),
-                    Duration::from_secs(5),
-                );
-            }
-        } else {
-            self.leader_id = Some(self.socket.local_addr().unwrap());
-            self.im_leader = true;
-            self.leader_order_processing.update_leader();
+        let own_addr = self.socket.local_addr().unwrap();
+        let im_leader = match self.leader_id {
+            Some(leader_id) => leader_id == own_addr,
+            None => self.raft.is_none() && self.become_leader(),
+        };
+        if im_leader {
             Logger.log(
                 LogLevel::LeaderInfo,
                 format!("Informing Robot {} that i`m the leader", msg.id).as_str(),
             );
-            let msg_leader = format!("Leader:{}", self.id);
+            let msg_leader = self.leader_message();
             let _ = self.send_message(msg_leader.clone(), msg.addr);
             self.ack_manager.add(
                 Ack::new(
@@ -251,9 +1105,57 @@ impl Robot {
                 ),
                 Duration::from_secs(5),
             );
-            self.leader_order_processing.send_work_to_robot(msg.addr);
-            self.leader_order_processing
-                .send_work_to_robot(self.socket.local_addr().unwrap());
+            self.register_robot(msg.addr, msg.capacity, msg.incarnation);
+            self.register_robot(own_addr, self.capacity, self.incarnation);
+        } else {
+            let msg_hello = format!("Hello:{}", self.id);
+            let _ = self.send_message(msg_hello.clone(), msg.addr);
+            self.ack_manager.add(
+                Ack::new(msg.addr, msg_hello.to_string().clone(), "Hello".to_string()),
+                Duration::from_secs(5),
+            );
+        }
+    }
+
+    /// Asume el rol de lider en una epoca nueva, levantando el actor que
+    /// atiende pedidos en su puerto de lider. Si no puede tomar ese puerto no
+    /// se postula.
+    fn become_leader(&mut self) -> bool {
+        match LeaderOrderProcessing::new(self.epoch + 1, self.leader_addr) {
+            Ok(leader) => {
+                self.leader_order_processing = Some(leader.start());
+                self.leader_endpoint = Some(self.leader_addr);
+                self.leader_id = Some(self.socket.local_addr().unwrap());
+                self.im_leader = true;
+                self.epoch += 1;
+                self.notify_interfaces();
+                true
+            }
+            Err(e) => {
+                Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] Could not take the leader port: {}", self.id, e).as_str(),
+                );
+                false
+            }
+        }
+    }
+
+    /// Actor del lider, solo si este robot es el lider. Con Raft todos los
+    /// robots tienen una replica, pero solo la del lider atiende.
+    fn leader_actor(&self) -> Option<&Addr<LeaderOrderProcessing>> {
+        self.leader_order_processing
+            .as_ref()
+            .filter(|_| self.im_leader)
+    }
+
+    fn register_robot(&self, addr: SocketAddr, capacity: usize, incarnation: u64) {
+        if let Some(leader) = self.leader_actor() {
+            leader.do_send(RegisterRobot {
+                addr,
+                capacity,
+                incarnation,
+            });
         }
     }
 
@@ -262,9 +1164,7 @@ impl Robot {
             LogLevel::Info,
             format!("[Robot {}] Received welcome from Robot {}", self.id, msg.id).as_str(),
         );
-        if !self.peers.contains(&msg.addr) {
-            self.peers.push(msg.addr);
-        }
+        self.add_member(msg.id, msg.addr);
         let _ = self.send_message("Ack:Hello".to_string(), msg.addr);
     }
 
@@ -281,14 +1181,16 @@ impl Robot {
         );
 
         if let Some(addr_leader) = self.leader_id {
-            let msg_use = format!("UseStock:{:?};{}", dto.ice_creams.join(","), mount);
+            let msg_use = format!(
+                "UseStock:{};{:?};{};{}",
+                dto.key(),
+                dto.ice_creams.join(","),
+                mount,
+                self.epoch
+            );
             let _ = self.send_message(msg_use.clone(), addr_leader);
             self.ack_manager.add(
-                Ack::new(
-                    addr_leader,
-                    msg_use.clone().to_string(),
-                    "UseStock".to_string(),
-                ),
+                Ack::new(addr_leader, msg_use, "UseStock".to_string()),
                 Duration::from_secs(5),
             );
         }
@@ -296,123 +1198,305 @@ impl Robot {
         Logger.log(LogLevel::ProcessingOrder, "Waiting for result checking...");
     }
 
+    /// Toma el proximo pedido de la cola y pide su stock, salvo que ya haya
+    /// uno esperando respuesta del lider.
+    fn start_next_order(&mut self) {
+        if self.current_order.is_some() {
+            return;
+        }
+        while let Some(dto) = self.pending_orders.pop_front() {
+            // La interfaz ya anulo el pago: se informa sin gastar stock
+            if dto.is_expired() {
+                Logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "[Robot {}] Order {} is past its deadline, skipping",
+                        self.id,
+                        dto.key()
+                    )
+                    .as_str(),
+                );
+                self.send_result_interface(&dto, false);
+                continue;
+            }
+            self.prepare_order(&dto);
+            self.current_order = Some(dto);
+            return;
+        }
+    }
+
+    fn has_order(&self, key: OrderKey) -> bool {
+        self.current_order
+            .as_ref()
+            .is_some_and(|dto| dto.key() == key)
+            || self.pending_orders.iter().any(|dto| dto.key() == key)
+    }
+
     fn handle_work(&mut self, msg: WorkMessage) {
         Logger.log(
             LogLevel::Info,
             format!(
                 "[Robot {}] Receiver working in order {}",
-                self.id, msg.dto.id_order
+                self.id,
+                msg.dto.key()
             )
             .as_str(),
         );
-        let _ = self.send_message("Ack:Work".to_string(), msg.addr);
-        self.prepare_order(&msg.dto);
-        self.current_order = Some(msg.dto.clone());
+        // Sin ack, asi un lider de una epoca vieja termina desistiendo
+        if msg.epoch < self.epoch {
+            Logger.log(
+                LogLevel::Error,
+                format!(
+                    "[Robot {}] Rejecting order {} from stale epoch {}",
+                    self.id,
+                    msg.dto.key(),
+                    msg.epoch
+                )
+                .as_str(),
+            );
+            return;
+        }
+        if msg.epoch > self.epoch {
+            if self.im_leader {
+                self.resign();
+            }
+            self.epoch = msg.epoch;
+        }
+        let _ = self.send_message(format!("Ack:Work:{}", msg.dto.key()), msg.addr);
+        if self.has_order(msg.dto.key()) {
+            return;
+        }
+        if self.draining_since.is_some() {
+            self.send_draining(vec![msg.dto.key()]);
+            return;
+        }
+        self.pending_orders.push_back(msg.dto);
+        self.start_next_order();
     }
 
     fn handle_availability(&mut self, msg: AvailabilityMessage) {
         Logger.log(
             LogLevel::Info,
             format!(
-                "[Robot {}] received availability message from Robot {}",
-                self.id, msg.addr
+                "[Robot {}] received availability message from Robot {} for order {}",
+                self.id, msg.addr, msg.key
             )
             .as_str(),
         );
-        self.leader_order_processing.finish_order(msg.addr);
-        self.leader_order_processing.send_work_to_robot(msg.addr);
-        let _ = self.send_message("Ack:Availability".to_string(), msg.addr);
+        // Sin actor de lider (por ejemplo durante un traspaso) no se confirma,
+        // asi el robot lo reenvia al lider que corresponda
+        if let Some(leader) = self.leader_actor() {
+            leader.do_send(OrderFinished {
+                addr: msg.addr,
+                key: msg.key,
+            });
+            let _ = self.send_message(format!("Ack:Availability:{}", msg.key), msg.addr);
+        }
     }
 
     fn handle_use_stock(&mut self, msg: UseStock) {
         Logger.log(
             LogLevel::LeaderInfo,
             format!(
-                "Received use stock {:?} and mount {}",
-                msg.ice_cream, msg.mount
+                "Received use stock {:?} and mount {} for order {}",
+                msg.ice_cream, msg.mount, msg.key
             )
             .as_str(),
         );
-        self.leader_order_processing
-            .use_stock(&msg.ice_cream, msg.mount, msg.addr);
-        let _ = self.send_message("Ack:UseStock".to_string(), msg.addr);
+        if let Some(leader) = self.leader_actor() {
+            leader.do_send(ReserveStock {
+                key: msg.key,
+                ice_creams: msg.ice_cream,
+                amount: msg.mount,
+                epoch: msg.epoch,
+                addr: msg.addr,
+            });
+            let _ = self.send_message("Ack:UseStock".to_string(), msg.addr);
+        }
+    }
+
+    fn handle_progress(&mut self, msg: ProgressMessage) {
+        if let Some(leader) = self.leader_actor() {
+            leader.do_send(ExtendDeadline {
+                addr: msg.addr,
+                key: msg.key,
+                extension: msg.extension,
+            });
+            let _ = self.send_message("Ack:Progress".to_string(), msg.addr);
+        }
+    }
+
+    fn send_progress(&mut self, key: OrderKey, extension: Duration) {
+        if self.im_leader {
+            self.handle_progress(ProgressMessage {
+                key,
+                extension,
+                addr: self.socket.local_addr().unwrap(),
+            });
+        } else if let Some(addr) = self.leader_id {
+            let msg = format!("Progress:{},{}", key, extension.as_secs());
+            let _ = self.send_message(msg.clone(), addr);
+            self.ack_manager.add(
+                Ack::new(addr, msg, "Progress".to_string()),
+                Duration::from_secs(5),
+            );
+        }
     }
 
     fn handle_stock_result(&mut self, msg: StockResult) {
-        if let Some(order) = &self.current_order {
-            let _ = self.send_message(format!("Ack:StockResult").to_string(), msg.addr);
-            if msg.result {
-                thread::sleep(std::time::Duration::from_secs(
-                    rand::thread_rng().gen_range(2, 4),
-                ));
+        let _ = self.send_message("Ack:StockResult".to_string(), msg.addr);
+        if msg.epoch < self.epoch {
+            Logger.log(
+                LogLevel::Error,
+                format!(
+                    "[Robot {}] Ignoring stock result for order {} from stale epoch {}",
+                    self.id, msg.key, msg.epoch
+                )
+                .as_str(),
+            );
+            return;
+        }
+        let order = match self.current_order.take() {
+            Some(order) if order.key() == msg.key => order,
+            other => {
+                self.current_order = other;
+                return;
+            }
+        };
+        // Se pide el stock del siguiente pedido mientras se prepara este
+        self.start_next_order();
+
+        let preparation = Duration::from_secs(if msg.result {
+            rand::thread_rng().gen_range(2, 4)
+        } else {
+            rand::thread_rng().gen_range(2, 3)
+        });
+        // Avisa al lider cuanto va a tardar para que no reasigne el pedido
+        self.send_progress(order.key(), preparation + Duration::from_secs(2));
+        self.preparing
+            .push(Preparation::new(order, msg.result, preparation));
+    }
+
+    /// Avanza las preparaciones en curso sin bloquear el actor: informa el
+    /// progreso de cada pedido y entrega los que ya terminaron.
+    fn check_preparations(&mut self) {
+        let (finished, preparing): (Vec<Preparation>, Vec<Preparation>) = self
+            .preparing
+            .drain(..)
+            .partition(|preparation| preparation.is_done());
+        self.preparing = preparing;
+
+        for preparation in self.preparing.iter() {
+            Logger.log(
+                LogLevel::ProcessingOrder,
+                format!(
+                    "[Robot {}] Order {} at {}%",
+                    self.id,
+                    preparation.order.key(),
+                    preparation.progress()
+                )
+                .as_str(),
+            );
+        }
+
+        for preparation in finished {
+            let order = preparation.order;
+            if preparation.result {
                 Logger.log(
                     LogLevel::Info,
                     format!(
                         "[Robot {}] Work complete for order {}",
-                        self.id, order.id_order
+                        self.id,
+                        order.key()
                     )
                     .as_str(),
                 );
-            } else {
-                thread::sleep(std::time::Duration::from_secs(
-                    rand::thread_rng().gen_range(2, 3),
-                ));
             }
-            let order_result = msg.result;
-            self.current_order_result = Some(order_result);
-            self.send_result_interface(order.id_interface, order.id_order, msg.result);
+            self.current_order_result = Some(preparation.result);
+            self.send_result_interface(&order, preparation.result);
         }
     }
 
-    fn send_availability(&mut self) {
+    fn send_availability(&mut self, key: OrderKey) {
+        self.delivering.remove(&key);
         if self.im_leader {
             self.handle_availability(AvailabilityMessage {
+                key,
                 addr: self.socket.local_addr().unwrap(),
             });
         } else if let Some(addr) = self.leader_id {
-            let _ = self.send_message("Availability".to_string(), addr);
+            let msg = format!("Availability:{}", key);
+            let _ = self.send_message(msg.clone(), addr);
             self.ack_manager.add(
-                Ack::new(addr, "Availability".to_string(), "Availability".to_string()),
+                Ack::new(addr, msg, format!("Availability:{}", key)),
                 Duration::from_secs(5),
             );
         }
-        self.current_order = None;
         self.current_order_result = None;
     }
 
-    fn send_result_interface(&mut self, id_interface: usize, id_order: usize, result: bool) {
-        let addr = format!("127.0.0.1:{}", 9000 + id_interface);
+    /// Le manda el resultado a la interfaz duena del pedido. Si no lo
+    /// confirma, el `AckManager` se lo pasa a las interfaces de reemplazo del
+    /// pedido.
+    fn send_result_interface(&mut self, order: &DTO, result: bool) {
+        let id_order = order.id_order;
         let message = format!("Robot:{},{}", id_order, result);
-        if let Some(addr_interface) = addr.to_socket_addrs().unwrap().next() {
-            if self.send_message(message.clone(), addr_interface).is_ok() {
-                self.ack_manager.add(
-                    Ack::new(
-                        addr_interface,
-                        message.clone(),
-                        "Result_Interface".to_string(),
-                    ),
-                    Duration::from_secs(5),
-                );
-            }
+        self.delivering.insert(order.key(), order.owner);
+        if self.send_message(message.clone(), order.owner).is_ok() {
+            self.ack_manager.add(
+                Ack::new(
+                    order.owner,
+                    message,
+                    format!("Result_Interface:{}", id_order),
+                )
+                .with_fallbacks(order.takeover.clone()),
+                Duration::from_secs(5),
+            );
         }
     }
 
+    /// Pedido en entrega al que corresponde un ack de una interfaz, que solo
+    /// conoce sus propios ids. Si se sabe, se busca por la interfaz duena.
+    fn delivered(&self, id_order: usize, owner: Option<SocketAddr>) -> Option<OrderKey> {
+        self.delivering
+            .iter()
+            .find(|(key, addr)| key.order == id_order && owner.is_none_or(|owner| owner == **addr))
+            .map(|(key, _)| *key)
+    }
+
     fn handle_ack(&mut self, msg: AckRobot) {
-        match msg.type_ack.as_str() {
-            "Announce" => self.ack_manager.remove(msg.type_ack, msg.addr),
-            "Availability" => self.ack_manager.remove(msg.type_ack, msg.addr),
-            "Hello" => self.ack_manager.remove(msg.type_ack, msg.addr),
-            "Leader" => self.ack_manager.remove(msg.type_ack, msg.addr),
-            "Result_Interface" => {
-                self.send_availability();
+        let (type_ack, id_order) = match msg.type_ack.split_once(':') {
+            Some((type_ack, id)) => (type_ack.to_string(), id.parse::<usize>().ok()),
+            None => (msg.type_ack.clone(), None),
+        };
+        match (type_ack.as_str(), id_order) {
+            ("Announce", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Availability", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Hello", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Join", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Members", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Leave", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Draining", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Leader", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Election", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("NewLeader", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Handover", _) => self.handle_handover_ack(msg.addr),
+            ("Result_Interface", Some(id_order)) => {
+                if let Some(key) = self.delivered(id_order, Some(msg.addr)) {
+                    self.send_availability(key);
+                }
                 self.ack_manager.remove(msg.type_ack, msg.addr);
             }
-            "Resilience" => {
+            ("Resilience", Some(id_order)) => {
                 Logger.log(LogLevel::Info, "Receive Ack Resilience Interface ");
-                self.send_availability();
+                if let Some(key) = self.delivered(id_order, None) {
+                    self.send_availability(key);
+                }
+                self.ack_manager.remove(msg.type_ack, msg.addr);
             }
-            "UseStock" => self.ack_manager.remove(msg.type_ack, msg.addr),
-            "StockResult" => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("UseStock", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("StockResult", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
+            ("Progress", _) => self.ack_manager.remove(msg.type_ack, msg.addr),
             _ => Logger.log(LogLevel::Error, "Error Ack"),
         }
     }
@@ -426,34 +1510,34 @@ impl Actor for Robot {
             LogLevel::Info,
             format!("Robot {} started", self.id).as_str(),
         );
-        let socket = match self.socket.try_clone() {
-            Ok(socket) => socket,
-            Err(e) => {
-                Logger.log(
-                    LogLevel::Error,
-                    format!("Error cloning socket: {}", e).as_str(),
-                );
-                return;
-            }
-        };
+        if self.raft.is_some() {
+            self.start_replica(_ctx);
+            _ctx.run_interval(RAFT_TICK, |act, _ctx| act.raft_tick());
+        }
+        let socket = Arc::clone(&self.socket);
         let id_robot = self.id;
         let actor_addr = _ctx.address();
 
         self.announce();
 
+        _ctx.run_interval(PREPARATION_TICK, |act, ctx| {
+            act.check_preparations();
+            act.check_drain(ctx);
+        });
+
         actix::spawn(async move {
-            let mut buffer = [0; 1024];
+            // Un traspaso de liderazgo lleva todo el estado del lider
+            let mut buffer = vec![0; 65536];
             loop {
                 Logger.log(
                     LogLevel::Info,
                     format!("[Robot {}] Waiting for message", id_robot).as_str(),
                 );
-                socket.set_read_timeout(None).unwrap();
 
-                match socket.recv_from(&mut buffer) {
+                match socket.recv_from(&mut buffer).await {
                     Ok((size, sender)) => {
                         let content = String::from_utf8_lossy(&buffer[..size]).to_string();
-                        actor_addr.send(Msg { content, sender }).await.unwrap();
+                        actor_addr.do_send(Msg { content, sender });
                     }
                     Err(e) => {
                         Logger.log(
@@ -479,60 +1563,206 @@ impl Handler<Msg> for Robot {
 
     fn handle(&mut self, msg: Msg, _ctx: &mut Self::Context) {
         if msg.content.starts_with("Announce:") {
-            let id: usize = msg.content[9..].parse().unwrap();
+            let content: Vec<&str> = msg.content[9..].split(',').collect();
+            let Ok(id) = content[0].parse() else {
+                return;
+            };
+            let capacity: usize = content.get(1).and_then(|c| c.parse().ok()).unwrap_or(1);
+            let incarnation: u64 = content.get(2).and_then(|i| i.parse().ok()).unwrap_or(0);
             _ctx.address().do_send(Announce {
                 id,
+                capacity,
+                incarnation,
                 addr: msg.sender,
             });
         } else if msg.content.starts_with("Election:") {
-            let id: usize = msg.content[9..].parse().unwrap();
-            _ctx.address().do_send(Election {
-                id,
-                sender: msg.sender,
-            });
+            if let Some((epoch, candidate)) = msg.content[9..].split_once(',') {
+                if let (Ok(epoch), Some(candidate)) = (epoch.parse(), Candidate::parse(candidate)) {
+                    _ctx.address().do_send(Election {
+                        candidate,
+                        epoch,
+                        sender: msg.sender,
+                    });
+                }
+            }
         } else if msg.content.starts_with("Leader:") {
-            let id: usize = msg.content[7..].parse().unwrap();
+            let content: Vec<&str> = msg.content[7..].split(',').collect();
+            let Ok(id) = content[0].parse::<usize>() else {
+                Logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "[Robot {}] Invalid {:?} from {}",
+                        self.id, msg.content, msg.sender
+                    )
+                    .as_str(),
+                );
+                return;
+            };
+            let epoch: u64 = content.get(1).and_then(|e| e.parse().ok()).unwrap_or(0);
+            let endpoint = content.get(2).and_then(|e| e.parse().ok());
             _ctx.address().do_send(Leader {
                 leader_id: id,
+                epoch,
+                endpoint,
+                sender: msg.sender,
+            });
+        } else if msg.content.starts_with("Join:") {
+            if let Some((id, addr)) = parse_member(&msg.content[5..]) {
+                _ctx.address().do_send(Join {
+                    id,
+                    addr,
+                    sender: msg.sender,
+                });
+            }
+        } else if msg.content.starts_with("Members:") {
+            let members = msg.content[8..]
+                .split(';')
+                .filter_map(parse_member)
+                .collect();
+            _ctx.address().do_send(Members {
+                members,
+                sender: msg.sender,
+            });
+        } else if msg.content.starts_with("Leave:") {
+            let Ok(id) = msg.content[6..].parse::<usize>() else {
+                Logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "[Robot {}] Invalid {:?} from {}",
+                        self.id, msg.content, msg.sender
+                    )
+                    .as_str(),
+                );
+                return;
+            };
+            _ctx.address().do_send(Leave {
+                id,
                 sender: msg.sender,
             });
+        } else if msg.content == "WhoIsLeader" || msg.content.starts_with("Order:") {
+            _ctx.address().do_send(WhoIsLeader { addr: msg.sender });
+        } else if msg.content == "Drain" {
+            _ctx.address().do_send(Drain);
+        } else if msg.content == "StepDown" || msg.content.starts_with("StepDown:") {
+            let successor = msg
+                .content
+                .strip_prefix("StepDown:")
+                .and_then(|id| id.parse().ok());
+            _ctx.address().do_send(StepDown { successor });
+        } else if msg.content.starts_with("Handover:") {
+            match serde_json::from_str::<LeaderSnapshot>(&msg.content[9..]) {
+                Ok(snapshot) => _ctx.address().do_send(Handover {
+                    snapshot,
+                    sender: msg.sender,
+                }),
+                Err(e) => Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] Invalid handover: {}", self.id, e).as_str(),
+                ),
+            }
+        } else if let Some(content) = msg.content.strip_prefix("Raft:") {
+            match serde_json::from_str::<RaftMessage>(content) {
+                Ok(message) => _ctx.address().do_send(RaftRpc {
+                    message,
+                    sender: msg.sender,
+                }),
+                Err(e) => Logger.log(
+                    LogLevel::Error,
+                    format!("[Robot {}] Invalid raft message: {}", self.id, e).as_str(),
+                ),
+            }
+        } else if msg.content.starts_with("Draining:") {
+            let handed_back = msg.content[9..]
+                .split(',')
+                .filter_map(|id| id.parse().ok())
+                .collect();
+            _ctx.address().do_send(Draining {
+                handed_back,
+                addr: msg.sender,
+            });
         } else if msg.content.starts_with("Hello:") {
-            let id: usize = msg.content[6..].parse().unwrap();
+            let Ok(id) = msg.content[6..].parse::<usize>() else {
+                Logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "[Robot {}] Invalid {:?} from {}",
+                        self.id, msg.content, msg.sender
+                    )
+                    .as_str(),
+                );
+                return;
+            };
             _ctx.address().do_send(Hello {
                 id,
                 addr: msg.sender,
             });
         } else if msg.content.starts_with("Work:") {
-            let content: String = msg.content[5..].parse().unwrap();
-            let dto = serde_json::from_str::<DTO>(content.as_str()).unwrap();
-            _ctx.address().do_send(WorkMessage {
-                dto,
+            if let Some((epoch, content)) = msg.content[5..].split_once(';') {
+                let (Ok(epoch), Ok(dto)) = (epoch.parse(), serde_json::from_str::<DTO>(content))
+                else {
+                    return;
+                };
+                _ctx.address().do_send(WorkMessage {
+                    dto,
+                    epoch,
+                    addr: msg.sender,
+                });
+            }
+        } else if msg.content.starts_with("Availability:") {
+            let Ok(key) = msg.content[13..].parse() else {
+                return;
+            };
+            _ctx.address().do_send(AvailabilityMessage {
+                key,
                 addr: msg.sender,
             });
-        } else if msg.content.starts_with("Availability") {
-            _ctx.address()
-                .do_send(AvailabilityMessage { addr: msg.sender });
         } else if msg.content.starts_with("UseStock:") {
             let content: Vec<&str> = msg.content[9..].split(';').collect();
-            if content.len() == 2 {
-                let ice_creams: Vec<&str> = content[0].split(',').collect(); // sabores
-                let mount = content[1].parse::<f64>().unwrap(); //cantidad
+            if content.len() == 4 {
+                // interfaz/pedido, cantidad y epoca del lider
+                let (Ok(key), Ok(mount), Ok(epoch)) =
+                    (content[0].parse(), content[2].parse(), content[3].parse())
+                else {
+                    return;
+                };
+                let ice_creams: Vec<&str> = content[1].split(',').collect(); // sabores
                 _ctx.address().do_send(UseStock {
+                    key,
                     ice_cream: ice_creams
                         .iter()
                         .map(|&s| s.trim_matches('"').to_string())
                         .collect(),
                     mount,
+                    epoch,
                     addr: msg.sender,
                 });
             }
         } else if msg.content.starts_with("StockResult:") {
-            let content: String = msg.content[12..].parse().unwrap();
-            let result: bool = content == "true";
-            _ctx.address().do_send(StockResult {
-                result,
-                addr: msg.sender,
-            });
+            let content: Vec<&str> = msg.content[12..].split(',').collect();
+            if content.len() == 3 {
+                let (Ok(key), Ok(epoch)) = (content[0].parse(), content[2].parse()) else {
+                    return;
+                };
+                let result: bool = content[1] == "true";
+                _ctx.address().do_send(StockResult {
+                    key,
+                    result,
+                    epoch,
+                    addr: msg.sender,
+                });
+            }
+        } else if msg.content.starts_with("Progress:") {
+            let content: Vec<&str> = msg.content[9..].split(',').collect();
+            if content.len() == 2 {
+                let (Ok(key), Ok(secs)) = (content[0].parse(), content[1].parse()) else {
+                    return;
+                };
+                _ctx.address().do_send(ProgressMessage {
+                    key,
+                    extension: Duration::from_secs(secs),
+                    addr: msg.sender,
+                });
+            }
         } else if msg.content.starts_with("Ack:") {
             let type_ack: String = msg.content[4..].parse().unwrap();
             _ctx.address().do_send(AckRobot {
@@ -546,8 +1776,8 @@ impl Handler<Msg> for Robot {
 impl Handler<Election> for Robot {
     type Result = ();
 
-    fn handle(&mut self, msg: Election, _ctx: &mut Self::Context) {
-        self.handle_election(msg)
+    fn handle(&mut self, msg: Election, ctx: &mut Self::Context) {
+        self.handle_election(msg, ctx)
     }
 }
 
@@ -559,6 +1789,14 @@ impl Handler<Leader> for Robot {
     }
 }
 
+impl Handler<WhoIsLeader> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: WhoIsLeader, _ctx: &mut Self::Context) {
+        self.handle_who_is_leader(msg)
+    }
+}
+
 impl Handler<Announce> for Robot {
     type Result = ();
 
@@ -567,6 +1805,62 @@ impl Handler<Announce> for Robot {
     }
 }
 
+impl Handler<Join> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Join, _ctx: &mut Self::Context) {
+        self.handle_join(msg)
+    }
+}
+
+impl Handler<Members> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Members, _ctx: &mut Self::Context) {
+        self.handle_members(msg)
+    }
+}
+
+impl Handler<Leave> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, ctx: &mut Self::Context) {
+        self.handle_leave(msg, ctx)
+    }
+}
+
+impl Handler<Drain> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Drain, _ctx: &mut Self::Context) {
+        self.start_drain()
+    }
+}
+
+impl Handler<StepDown> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: StepDown, ctx: &mut Self::Context) {
+        self.step_down(msg, ctx)
+    }
+}
+
+impl Handler<Handover> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Handover, ctx: &mut Self::Context) {
+        self.handle_handover(msg, ctx)
+    }
+}
+
+impl Handler<Draining> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Draining, _ctx: &mut Self::Context) {
+        self.handle_draining(msg)
+    }
+}
+
 impl Handler<Hello> for Robot {
     type Result = ();
 
@@ -607,6 +1901,14 @@ impl Handler<StockResult> for Robot {
     }
 }
 
+impl Handler<ProgressMessage> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProgressMessage, _ctx: &mut Self::Context) -> Self::Result {
+        self.handle_progress(msg);
+    }
+}
+
 impl Handler<AckRobot> for Robot {
     type Result = ();
 
@@ -614,3 +1916,98 @@ impl Handler<AckRobot> for Robot {
         self.handle_ack(msg);
     }
 }
+
+impl Handler<RaftRpc> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: RaftRpc, _ctx: &mut Self::Context) {
+        self.handle_raft(msg)
+    }
+}
+
+impl Handler<Propose> for Robot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Propose, _ctx: &mut Self::Context) {
+        self.propose(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn order(id: usize) -> DTO {
+        DTO {
+            id_order: id,
+            id_interface: 1,
+            ice_creams: vec!["Chocolate".to_string()],
+            size_order: 0.5,
+            cash_card: 0,
+            total_amount: 850,
+            owner: addr(9001),
+            takeover: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    /// Robot en puertos libres, sin semillas, que sigue al lider dado en la
+    /// epoca dada.
+    fn follower(leader: SocketAddr, epoch: u64) -> Robot {
+        let mut config = RobotConfig::new(1).unwrap();
+        config.addr = addr(0);
+        config.leader_addr = addr(0);
+        config.seeds = Vec::new();
+        let mut robot = Robot::new(config).unwrap();
+        robot.leader_id = Some(leader);
+        robot.epoch = epoch;
+        robot
+    }
+
+    #[actix_rt::test]
+    async fn messages_from_a_stale_epoch_are_rejected() {
+        let leader = addr(5002);
+        let mut robot = follower(leader, 3);
+        robot.current_order = Some(order(1));
+
+        robot.handle_work(WorkMessage {
+            dto: order(2),
+            epoch: 2,
+            addr: addr(5001),
+        });
+        assert!(!robot.has_order(order(2).key()));
+
+        robot.handle_stock_result(StockResult {
+            key: order(1).key(),
+            result: true,
+            epoch: 2,
+            addr: addr(5001),
+        });
+        assert_eq!(
+            robot.current_order.as_ref().map(DTO::key),
+            Some(order(1).key())
+        );
+
+        robot.handle_leader(Leader {
+            leader_id: 9,
+            epoch: 2,
+            endpoint: Some(addr(5009)),
+            sender: addr(6009),
+        });
+        assert_eq!(robot.leader_id, Some(leader));
+        assert_eq!(robot.epoch, 3);
+
+        // El mismo trabajo con la epoca vigente si se acepta
+        robot.handle_work(WorkMessage {
+            dto: order(2),
+            epoch: 3,
+            addr: leader,
+        });
+        assert!(robot.has_order(order(2).key()));
+        robot.ack_manager.stop();
+    }
+}
@@ -0,0 +1,59 @@
+use std::net::SocketAddr;
+
+use crate::common::udp::local_addr;
+use crate::structures::election::ElectionAlgorithm;
+
+const DEFAULT_CAPACITY: usize = 2;
+const DEFAULT_SEED_IDS: std::ops::Range<usize> = 1..10;
+
+/// Configuracion con la que arranca un robot.
+#[derive(Debug, Clone)]
+pub struct RobotConfig {
+    pub id: usize,
+    pub capacity: usize,
+    pub priority: u32,
+    pub prefer_fresh_state: bool,
+    pub election: ElectionAlgorithm,
+    pub raft: bool,
+    /// Archivo donde se guarda el estado de Raft.
+    pub raft_log: String,
+    pub addr: SocketAddr,
+    pub leader_addr: SocketAddr,
+    pub seeds: Vec<SocketAddr>,
+}
+
+/// Puerto por defecto de un robot segun su id. Falla si el id no entra en
+/// un puerto.
+pub fn default_robot_addr(id: usize) -> Result<SocketAddr, String> {
+    local_addr(6000, id)
+}
+
+/// Puerto por defecto en el que un robot atiende como lider segun su id.
+pub fn default_leader_addr(id: usize) -> Result<SocketAddr, String> {
+    local_addr(5000, id)
+}
+
+impl RobotConfig {
+    /// Configuracion por defecto: escucha en 6000+id, atiende como lider en
+    /// 5000+id, elige lider en anillo y usa como semillas los puertos por
+    /// defecto de los robots 1 a 9. Con Raft guarda su estado en
+    /// `raft_<id>.jsonl`. Falla si el id no entra en esos puertos.
+    pub fn new(id: usize) -> Result<RobotConfig, String> {
+        let addr = default_robot_addr(id)?;
+        Ok(RobotConfig {
+            id,
+            capacity: DEFAULT_CAPACITY,
+            priority: 0,
+            prefer_fresh_state: false,
+            election: ElectionAlgorithm::Ring,
+            raft: false,
+            raft_log: format!("raft_{}.jsonl", id),
+            addr,
+            leader_addr: default_leader_addr(id)?,
+            seeds: DEFAULT_SEED_IDS
+                .filter_map(|seed| default_robot_addr(seed).ok())
+                .filter(|seed| *seed != addr)
+                .collect(),
+        })
+    }
+}
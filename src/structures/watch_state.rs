@@ -0,0 +1,213 @@
+use crate::common::read_file::OrderFormat;
+use crate::structures::order::Order;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Lo que una interfaz ya tomo de la carpeta que vigila. Se guarda en la misma
+/// carpeta para que al reiniciar no se vuelvan a mandar archivos ni pedidos.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WatchState {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Nombres de los archivos ya procesados (tambien los que no se pudieron
+    /// leer, para no reintentarlos).
+    pub files: BTreeSet<String>,
+    /// Ids de los pedidos ya mandados al gateway, con el archivo del que
+    /// salieron.
+    pub orders: BTreeMap<usize, String>,
+}
+
+/// Pedidos de un archivo separados segun si ya se habian mandado.
+#[derive(Debug, Default)]
+pub struct TakenOrders {
+    /// Pedidos nuevos, para mandar.
+    pub new: Vec<Order>,
+    /// Ids que ya se mandaron desde el mismo archivo, por ejemplo si la
+    /// interfaz se cayo antes de marcarlo como procesado.
+    pub repeated: Vec<usize>,
+    /// Ids que ya uso otro archivo, con el nombre de ese archivo. Estos
+    /// pedidos no se mandan.
+    pub collisions: Vec<(usize, String)>,
+}
+
+impl WatchState {
+    /// Archivo de estado de la interfaz `id` dentro de `dir`.
+    pub fn path(dir: &Path, id: usize) -> PathBuf {
+        dir.join(format!(".processed_{}.json", id))
+    }
+
+    /// Lee el estado guardado o arranca uno vacio si todavia no hay.
+    pub fn load(path: PathBuf) -> io::Result<WatchState> {
+        let mut state = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => WatchState::default(),
+            Err(e) => return Err(e),
+        };
+        state.path = path;
+        Ok(state)
+    }
+
+    /// Anota como mandados los pedidos nuevos de `file` y los separa de los
+    /// que ya se habian mandado.
+    pub fn take_orders(&mut self, file: &str, orders: Vec<Order>) -> TakenOrders {
+        let mut taken = TakenOrders::default();
+        for order in orders {
+            match self.orders.get(&order.id) {
+                Some(previous) if previous == file => taken.repeated.push(order.id),
+                Some(previous) => taken.collisions.push((order.id, previous.clone())),
+                None => {
+                    self.orders.insert(order.id, file.to_string());
+                    taken.new.push(order);
+                }
+            }
+        }
+        taken
+    }
+
+    /// Guarda el estado. Se escribe en un temporal y se renombra para que un
+    /// corte no deje el archivo a medias.
+    pub fn save(&self) -> io::Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Carpeta vigilada. Un archivo se toma recien cuando su tamaño y fecha de
+/// modificacion no cambiaron entre dos revisiones, para no leerlo mientras se
+/// esta copiando.
+#[derive(Debug)]
+pub struct WatchFolder {
+    dir: PathBuf,
+    pub state: WatchState,
+    seen: HashMap<String, (u64, SystemTime)>,
+}
+
+impl WatchFolder {
+    pub fn new(dir: PathBuf, id: usize) -> io::Result<WatchFolder> {
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+        let state = WatchState::load(WatchState::path(&dir, id))?;
+        Ok(WatchFolder {
+            dir,
+            state,
+            seen: HashMap::new(),
+        })
+    }
+
+    /// Archivos de pedidos nuevos listos para leer, ordenados por nombre.
+    pub fn ready_files(&mut self) -> io::Result<Vec<(String, PathBuf)>> {
+        let mut ready = Vec::new();
+        let mut seen = HashMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            // Se ignoran los ocultos (como el estado) y los que no son pedidos
+            if name.starts_with('.')
+                || self.state.files.contains(&name)
+                || OrderFormat::from_path(&path.to_string_lossy()).is_none()
+            {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let stamp = (metadata.len(), metadata.modified()?);
+            if self.seen.get(&name) == Some(&stamp) {
+                ready.push((name, path));
+            } else {
+                seen.insert(name, stamp);
+            }
+        }
+        self.seen = seen;
+        ready.sort();
+        Ok(ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::read_file::read_file;
+
+    /// Archivo JSON con un pedido por id.
+    fn orders_file(ids: &[usize]) -> String {
+        let orders: Vec<String> = ids
+            .iter()
+            .map(|id| {
+                format!(
+                    r#"{{"id": {}, "client_id": 1, "ice_creams": ["Chocolate"], "size_order": 0.5, "cash_card": 2000}}"#,
+                    id
+                )
+            })
+            .collect();
+        format!(r#"{{"orders": [{}]}}"#, orders.join(","))
+    }
+
+    /// Revisa la carpeta dos veces, como el timer de la interfaz, y toma los
+    /// pedidos de los archivos listos.
+    fn scan(watch: &mut WatchFolder) -> Vec<(String, TakenOrders)> {
+        assert!(watch.ready_files().unwrap().is_empty());
+        let mut taken = Vec::new();
+        for (name, path) in watch.ready_files().unwrap() {
+            let file = read_file(&path.to_string_lossy(), None).unwrap();
+            taken.push((name.clone(), watch.state.take_orders(&name, file.orders)));
+            watch.state.files.insert(name);
+            watch.state.save().unwrap();
+        }
+        taken
+    }
+
+    fn ids(orders: &[Order]) -> Vec<usize> {
+        orders.iter().map(|order| order.id).collect()
+    }
+
+    #[test]
+    fn watched_files_are_taken_once_and_reused_ids_are_reported() {
+        let dir = std::env::temp_dir().join(format!("watch_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a.json"), orders_file(&[1, 2])).unwrap();
+
+        let mut watch = WatchFolder::new(dir.clone(), 1).unwrap();
+        let taken = scan(&mut watch);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].0, "a.json");
+        assert_eq!(ids(&taken[0].1.new), vec![1, 2]);
+
+        // Al reiniciar, el archivo ya tomado no se vuelve a leer
+        let mut watch = WatchFolder::new(dir.clone(), 1).unwrap();
+        assert!(scan(&mut watch).is_empty());
+
+        fs::write(dir.join("b.json"), orders_file(&[2, 3])).unwrap();
+        let taken = scan(&mut watch);
+        assert_eq!(taken.len(), 1);
+        let (name, taken) = &taken[0];
+        assert_eq!(name, "b.json");
+        assert_eq!(ids(&taken.new), vec![3]);
+        assert!(taken.repeated.is_empty());
+        assert_eq!(taken.collisions, vec![(2, "a.json".to_string())]);
+
+        // Si se cae antes de marcar un archivo, al releerlo no repite pedidos
+        let mut state = WatchState::load(WatchState::path(&dir, 1)).unwrap();
+        let file = read_file(&dir.join("b.json").to_string_lossy(), None).unwrap();
+        let taken = state.take_orders("b.json", file.orders);
+        assert!(taken.new.is_empty());
+        assert_eq!(taken.repeated, vec![3]);
+        assert_eq!(taken.collisions, vec![(2, "a.json".to_string())]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}